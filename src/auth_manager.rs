@@ -5,57 +5,332 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use mvdb::Mvdb;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64;
 use geeny_api::ConnectApi;
 use geeny_api::models::AuthLoginResponse;
 use log;
-use std::thread;
+use serde_json;
+
+use broadcast::Broadcast;
+use errors::*;
 use interface;
+use secrets::{SecretStore, TOKEN_KEY};
+use signal::Signal;
+use storage::{self, Document, Migration, Store};
+
+/// All auth events are published with this key
+pub const AUTH_EVENT_KEY: &str = "auth";
+
+// How often to check whether a user has logged in
+const NO_TOKEN_POLL_SECS: u64 = 5 * 60; // 5 minutes
+
+// Never sleep longer than this, even if the token is valid for much longer
+const MAX_SLEEP_SECS: u64 = 24 * 60 * 60; // One day
+
+// Tokens without a readable lifetime are refreshed once they are this close to expiring
+const DEFAULT_REFRESH_WINDOW_SECS: u64 = 60 * 60; // One hour
+
+// Failed refreshes are retried after this delay, doubling with every failure
+const RETRY_BASE_SECS: u64 = 30;
+const RETRY_MAX_SECS: u64 = 30 * 60; // 30 minutes
 
 #[derive(Debug, Serialize, Deserialize, Clone, Hash, Default)]
 pub struct ServiceCredentials {
     pub username: String,
+
+    // Tokens are kept in the `SecretStore`. This is only read to move tokens
+    // stored by previous versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub token: Option<String>,
 }
 
+impl Document for ServiceCredentials {
+    fn migrations() -> Vec<Migration> {
+        vec![storage::add_version]
+    }
+}
+
+/// Thread safe handle to the login state. The username is stored in the
+/// credentials file, the token in the `SecretStore`
+#[derive(Clone)]
+pub struct Credentials {
+    info: Store<ServiceCredentials>,
+    secrets: Arc<SecretStore>,
+
+    // All changes of the token go through this handle, so it is only read once
+    token: Arc<Mutex<Option<String>>>,
+}
+
+impl Credentials {
+    pub fn new(info: Store<ServiceCredentials>, secrets: Arc<SecretStore>) -> Result<Self> {
+        let legacy_token = info.access(|creds| creds.token.clone())?;
+        if let Some(tkn) = legacy_token {
+            log::info!("Moving token to the secret store");
+            secrets.put(TOKEN_KEY, tkn.as_bytes())?;
+            info.access_mut(|creds| creds.token = None)?;
+        }
+
+        let token = match secrets.get(TOKEN_KEY)? {
+            Some(tkn) => Some(String::from_utf8(tkn).chain_err(|| "Stored token is invalid")?),
+            None => None,
+        };
+
+        Ok(Credentials {
+            info: info,
+            secrets: secrets,
+            token: Arc::new(Mutex::new(token)),
+        })
+    }
+
+    fn lock_token(&self) -> MutexGuard<'_, Option<String>> {
+        // The token is only replaced as a whole, a panic can't leave it inconsistent
+        self.token.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn store_token(&self, token: Option<&String>) -> Result<()> {
+        match token {
+            Some(tkn) => self.secrets.put(TOKEN_KEY, tkn.as_bytes()),
+            None => self.secrets.delete(TOKEN_KEY),
+        }
+    }
+
+    pub fn username(&self) -> Result<String> {
+        self.info.access(|creds| creds.username.clone())
+    }
+
+    pub fn token(&self) -> Option<String> {
+        self.lock_token().clone()
+    }
+
+    /// Store the username and token of a new login
+    pub fn login(&self, username: &str, token: String) -> Result<()> {
+        let mut current = self.lock_token();
+        self.store_token(Some(&token))?;
+        *current = Some(token);
+
+        self.info.access_mut(|creds| creds.username = username.into())?;
+        Ok(())
+    }
+
+    /// Forget the username and token
+    pub fn logout(&self) -> Result<()> {
+        let mut current = self.lock_token();
+        self.store_token(None)?;
+        *current = None;
+
+        self.info.access_mut(|creds| creds.username = "".into())?;
+        Ok(())
+    }
+
+    /// Replace the token, unless it was changed since `expected` was read, e.g.
+    /// by a new login. Returns `true` if the token was replaced
+    pub fn replace_token(&self, expected: &str, token: Option<String>) -> Result<bool> {
+        let mut current = self.lock_token();
+        if current.as_ref().map(|tkn| tkn.as_str()) != Some(expected) {
+            return Ok(false);
+        }
+
+        self.store_token(token.as_ref())?;
+        *current = token;
+        Ok(true)
+    }
+
+    /// Write out the current state of the credentials file
+    pub fn flush(&self) -> Result<()> {
+        self.info.access_mut(|_| ())
+    }
+}
+
+/// Changes of the login state of the SDK
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuthEvent {
+    /// A user logged in with `HubSDK::login`
+    LoggedIn { username: String },
+
+    /// The user logged out with `HubSDK::logout`
+    LoggedOut,
+
+    /// The token was refreshed, and stays valid
+    TokenRefreshed,
+
+    /// The Geeny cloud rejected the token, e.g. because it expired. A new login is required
+    TokenExpired,
+
+    /// An attempt to refresh the token failed, e.g. because the network is
+    /// unavailable. It is retried until the Geeny cloud refreshes or rejects the token
+    RefreshFailed { reason: String },
+}
+
+/// The parts of the JWT payload used to schedule refreshes
+#[derive(Deserialize)]
+struct TokenClaims {
+    // Seconds since the unix epoch
+    exp: Option<u64>,
+    iat: Option<u64>,
+}
+
+/// The result of an attempt to refresh the token
+pub enum Refresh {
+    /// The Geeny cloud issued a new token
+    Refreshed(String),
+
+    /// The Geeny cloud rejected the token, it will never become valid again
+    Rejected,
+
+    /// The refresh failed for another reason, e.g. the network being unavailable
+    Failed(String),
+}
+
 // TODO DI-246:
-//   * This needs some love. We should probably immediately retry a few times if the token is invalid
-//   * Inverse Exponential Backoff (try more often the closer we are to expiration)
-//   * Provide a way to instantly invalidate the token, e.g., if requests fail to 40x errors (bad auth)
-//   * Purge unwraps
 //   * cleanup dead paths here, remove password from storage structure
-//   * granularity between bad request, bad token, etc (also DI-234, enumerated error types)
-pub fn auth_manager(config: interface::HubSDKConfig, auth: &Mvdb<ServiceCredentials>) {
+pub fn auth_manager(
+    config: interface::HubSDKConfig,
+    auth: &Credentials,
+    events: &Broadcast<AuthEvent>,
+    signal: &Signal,
+) {
     let server = config.connect_api;
+    let mut failures = 0;
+
     loop {
-        let creds = auth.access(|auth| auth.clone()).unwrap();
+        let sleep_secs = match auth.token() {
+            None => {
+                failures = 0;
+                NO_TOKEN_POLL_SECS
+            }
+            Some(ref tkn) => {
+                let now = now_secs();
+                let expiry = token_expiry(tkn);
 
-        let new_tkn = match creds.token {
-            None => None,
-            Some(ref tkn) => check_and_refresh(&server, tkn),
-        };
+                // Without a known expiry, refresh on start and once a day
+                let due_in = expiry
+                    .map(|(exp, window)| exp.saturating_sub(now).saturating_sub(window))
+                    .unwrap_or(0);
 
-        let backoff_ms: u32 = match new_tkn {
-            Some(_) => (24 * 60 * 60 * 1000), // One day
-            None => (5 * 60 * 1000), // 5 minutes
+                if due_in > 0 {
+                    due_in
+                } else {
+                    let (new_tkn, event) = match check_and_refresh(&server, tkn) {
+                        Refresh::Refreshed(new_tkn) => {
+                            failures = 0;
+                            (Some(new_tkn), AuthEvent::TokenRefreshed)
+                        }
+                        Refresh::Rejected => {
+                            log::warn!("Token was rejected, please log in again");
+                            failures = 0;
+                            (None, AuthEvent::TokenExpired)
+                        }
+                        // The token is only dropped once the Geeny cloud rejects it,
+                        // the local clock may be wrong
+                        Refresh::Failed(reason) => {
+                            failures += 1;
+                            (Some(tkn.clone()), AuthEvent::RefreshFailed { reason: reason })
+                        }
+                    };
+
+                    // Don't overwrite a token stored by a login in the meantime
+                    let current = if new_tkn.as_ref() != Some(tkn) {
+                        auth.replace_token(tkn, new_tkn.clone())
+                            .unwrap_or_else(|e| {
+                                log::error!("Failed to store token: {}", e);
+                                false
+                            })
+                    } else {
+                        true
+                    };
+
+                    if current {
+                        events.publish(AUTH_EVENT_KEY, &event);
+
+                        // Let the things database pick up the new token
+                        signal.wake();
+                    }
+
+                    match new_tkn {
+                        None => NO_TOKEN_POLL_SECS,
+                        Some(ref new_tkn) if failures == 0 => {
+                            // Don't hammer the cloud with short lived tokens
+                            token_expiry(new_tkn)
+                                .map(|(exp, window)| exp.saturating_sub(now).saturating_sub(window))
+                                .unwrap_or(MAX_SLEEP_SECS)
+                                .max(RETRY_BASE_SECS)
+                        }
+                        Some(_) => {
+                            let retry = retry_delay(failures);
+
+                            // Make sure there is one last attempt before the token expires
+                            match expiry {
+                                Some((exp, _)) if exp > now => retry.min(exp - now),
+                                _ => retry,
+                            }
+                        }
+                    }
+                }
+            }
         };
 
-        if new_tkn != creds.token {
-            auth.access_mut(|auth| auth.token = new_tkn).unwrap();
-        }
+        let sleep_secs = sleep_secs.max(1).min(MAX_SLEEP_SECS);
 
-        log::info!("auth manager sleeping for {} seconds...", backoff_ms);
-        #[allow(deprecated)] thread::sleep_ms(backoff_ms);
+        log::info!("auth manager sleeping for {} seconds...", sleep_secs);
+        if signal.sleep(Duration::from_secs(sleep_secs)) {
+            log::info!("auth manager stopped");
+            return;
+        }
     }
 }
 
-pub fn check_and_refresh(server: &ConnectApi, auth: &str) -> Option<String> {
+pub fn check_and_refresh(server: &ConnectApi, auth: &str) -> Refresh {
     log::info!("Refreshing current token");
     match server.refresh_token(&AuthLoginResponse { token: auth.into() }) {
-        Ok(tkn) => Some(tkn.token),
+        Ok(tkn) => Refresh::Refreshed(tkn.token),
         Err(e) => {
-            log::error!("Token refresh failed: {:?}", e);
-            None
+            let err = api_error(e);
+            log::error!("Token refresh failed: {}", err);
+
+            match *err.kind() {
+                ErrorKind::InvalidCredentials => Refresh::Rejected,
+                _ => Refresh::Failed(err.to_string()),
+            }
         }
     }
 }
+
+/// Decode the expiry of a JWT, along with how long before the expiry the token
+/// should be refreshed. Returns `None` if the token carries no expiry
+fn token_expiry(token: &str) -> Option<(u64, u64)> {
+    let payload = token.split('.').nth(1)?;
+
+    // JWTs use unpadded base64url encoding
+    let mut padded = payload.to_string();
+    while padded.len() % 4 != 0 {
+        padded.push('=');
+    }
+
+    let decoded = base64::decode_config(&padded, base64::URL_SAFE).ok()?;
+    let claims: TokenClaims = serde_json::from_slice(&decoded).ok()?;
+    let exp = claims.exp?;
+
+    // Refresh during the last quarter of the token's lifetime
+    let window = match claims.iat {
+        Some(iat) if iat < exp => (exp - iat) / 4,
+        _ => DEFAULT_REFRESH_WINDOW_SECS,
+    };
+
+    Some((exp, window))
+}
+
+fn retry_delay(failures: u32) -> u64 {
+    let shift = failures.saturating_sub(1).min(16);
+    (RETRY_BASE_SECS << shift).min(RETRY_MAX_SECS)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
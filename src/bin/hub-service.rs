@@ -36,6 +36,7 @@ mod allocator {
 #[allow(unused_imports)]
 use allocator::THE_ALLOC;
 
+extern crate ctrlc;
 extern crate hub_sdk;
 extern crate mvdb;
 
@@ -44,6 +45,7 @@ extern crate log;
 extern crate env_logger;
 
 use std::path::PathBuf;
+use std::process;
 use std::thread;
 
 use hub_sdk::services::rest_ipc::{prep_rocket, ServiceConfig};
@@ -64,6 +66,19 @@ fn main() {
 
     let hub_sdk = HubSDK::new(sdk_cfg);
 
+    // Stop cleanly on SIGINT or SIGTERM, e.g. when stopped by systemd
+    let shutdown_sdk = hub_sdk.clone();
+    ctrlc::set_handler(move || {
+        log::info!("Stopping Hub SDK Service");
+
+        if let Err(e) = shutdown_sdk.shutdown() {
+            log::error!("Failed to stop Hub SDK: {}", e);
+            process::exit(1);
+        }
+
+        process::exit(0);
+    }).expect("Failed to install signal handler");
+
     let x = prep_rocket(ipc_cfg, hub_sdk.clone());
 
     let ipc_api = thread::spawn(|| { x.launch(); });
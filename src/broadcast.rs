@@ -0,0 +1,122 @@
+// Copyright 2017 Telefónica Germany Next GmbH. See the COPYRIGHT file at
+// the top-level directory of this distribution
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Delivery of events to any number of subscribers, as they occur
+
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log;
+
+// Events waiting to be received by a single subscriber. Further events are
+// dropped for that subscriber, e.g. a stalled client of an event stream
+const SUBSCRIBER_CAPACITY: usize = 1024;
+
+struct Subscriber<T> {
+    // Only events published with this key are delivered. `None` receives all events
+    key: Option<String>,
+    sender: SyncSender<T>,
+}
+
+/// Thread safe handle used to publish events to all current subscribers
+pub struct Broadcast<T> {
+    subscribers: Arc<Mutex<Vec<Subscriber<T>>>>,
+}
+
+impl<T> Clone for Broadcast<T> {
+    fn clone(&self) -> Self {
+        Broadcast { subscribers: self.subscribers.clone() }
+    }
+}
+
+impl<T> Default for Broadcast<T> {
+    fn default() -> Self {
+        Broadcast { subscribers: Arc::new(Mutex::new(vec![])) }
+    }
+}
+
+impl<T: Clone> Broadcast<T> {
+    /// Subscribe to events with a given key, or to all events if no key is given
+    pub fn subscribe(&self, key: Option<String>) -> Subscription<T> {
+        let (tx, rx) = sync_channel(SUBSCRIBER_CAPACITY);
+
+        // A panic while holding the lock cannot leave the list inconsistent
+        let mut subs = self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        subs.push(Subscriber {
+            key: key,
+            sender: tx,
+        });
+
+        Subscription { receiver: rx }
+    }
+
+    /// Deliver an event to all matching subscribers. Subscribers which have
+    /// been dropped are removed, subscribers which fell too far behind miss
+    /// the event
+    pub fn publish(&self, key: &str, event: &T) {
+        let mut subs = self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        subs.retain(|sub| {
+            let wanted = match sub.key {
+                Some(ref k) => k == key,
+                None => true,
+            };
+            if !wanted {
+                return true;
+            }
+
+            match sub.sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("Subscriber for {} is not keeping up, dropping an event", key);
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    /// Remove all subscribers. Their subscriptions end once all pending
+    /// events have been received
+    pub fn close(&self) {
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+}
+
+/// A stream of events delivered as they occur. Dropping the `Subscription`
+/// ends the subscription.
+///
+/// `Subscription` may be used as a blocking iterator, which ends when the
+/// `HubSDK` is no longer running
+pub struct Subscription<T> {
+    receiver: Receiver<T>,
+}
+
+impl<T> Subscription<T> {
+    /// Wait for the next event. Returns `None` if no further events will arrive
+    pub fn recv(&self) -> Option<T> {
+        self.receiver.recv().ok()
+    }
+
+    /// Wait up to `timeout` for the next event
+    pub fn recv_timeout(&self, timeout: Duration) -> ::std::result::Result<T, RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout)
+    }
+
+    /// Obtain the next event if one is already waiting, without blocking
+    pub fn try_recv(&self) -> Option<T> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+impl<T> Iterator for Subscription<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.recv()
+    }
+}
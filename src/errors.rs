@@ -31,7 +31,9 @@
 //! ```
 //!
 //! Additionally, `Error` implements the `Responder` trait from Rocket, and will produce
-//! a JSON return value with error code 400 if returned from a route handler in Rocket.
+//! a JSON return value if returned from a route handler in Rocket. The HTTP status and
+//! the `code` field of the JSON body are chosen based on the `ErrorKind`, e.g. 404 and
+//! `"unknown_serial"` for `ErrorKind::UnknownSerial`.
 
 #[cfg(feature = "rest-service")]
 use std::io::Cursor;
@@ -46,15 +48,181 @@ use rocket::response::{Responder, Response};
 #[cfg(feature = "rest-service")]
 use rocket::http::{ContentType, Status};
 
+use std::path::PathBuf;
+
 use mvdb::errors as merr;
 use geeny_api::errors as gerr;
+use uuid::Uuid;
+
+use things_db::ThingState;
 
-// TODO DI-234 - Proper enumeration of errors
 error_chain!{
     links {
         Mvdb(merr::Error, merr::ErrorKind);
         GeenyApi(gerr::Error, gerr::ErrorKind);
     }
+
+    errors {
+        /// A folder required by the SDK does not exist, and could not be created
+        MissingDirectory(path: PathBuf) {
+            description("missing directory")
+            display("missing directory: {:?}", path)
+        }
+
+        /// The SDK is not allowed to access or create a file or folder
+        PermissionDenied(path: PathBuf) {
+            description("permission denied")
+            display("permission denied: {:?}", path)
+        }
+
+        /// The element file exists, but its contents could not be read, and no
+        /// backup could be restored. Please see `HubSDKConfig::reset_corrupt_storage`
+        CorruptElementFile(path: PathBuf) {
+            description("corrupt element file")
+            display("corrupt element file: {:?}", path)
+        }
+
+        /// The credentials file exists, but its contents could not be read, and no
+        /// backup could be restored. Please see `HubSDKConfig::reset_corrupt_storage`
+        CorruptCredentialsFile(path: PathBuf) {
+            description("corrupt credentials file")
+            display("corrupt credentials file: {:?}", path)
+        }
+
+        /// Other data persisted by the SDK could not be read, or could not be
+        /// upgraded from a previous version of the SDK
+        StorageCorrupt(path: PathBuf) {
+            description("corrupt storage")
+            display("corrupt storage: {:?}", path)
+        }
+
+        /// No user is logged in, or the login has expired. Please see `HubSDK::login`
+        NotLoggedIn {
+            description("not logged in")
+            display("not logged in, please log in")
+        }
+
+        /// The Geeny cloud rejected the given credentials or token
+        InvalidCredentials {
+            description("invalid credentials")
+            display("invalid credentials")
+        }
+
+        /// The Geeny cloud could not be reached
+        NetworkUnavailable {
+            description("network unavailable")
+            display("the Geeny cloud could not be reached")
+        }
+
+        /// The Geeny cloud rejected a request, with the given HTTP status
+        CloudRejected(status: u16) {
+            description("request rejected by the Geeny cloud")
+            display("the Geeny cloud rejected the request with status {}", status)
+        }
+
+        /// A thing with this serial number is already managed by the SDK
+        DuplicateThing(serial_number: String) {
+            description("duplicate thing")
+            display("a thing with serial number {} already exists", serial_number)
+        }
+
+        /// The request to create a thing is incomplete, e.g. without a serial number
+        InvalidThingRequest(reason: String) {
+            description("invalid thing request")
+            display("invalid thing request: {}", reason)
+        }
+
+        /// No thing with this serial number is managed by the SDK
+        UnknownSerial(serial_number: String) {
+            description("unknown serial number")
+            display("no thing with serial number {}", serial_number)
+        }
+
+        /// No thing with this Geeny Thing ID is managed by the SDK
+        UnknownThingId(thing_id: Uuid) {
+            description("unknown thing id")
+            display("no thing with thing id {}", thing_id)
+        }
+
+        /// The thing has not yet completed provisioning with the Geeny cloud
+        ThingNotActive(serial_number: String) {
+            description("thing not active")
+            display("thing with serial number {} is not active", serial_number)
+        }
+
+        /// The Geeny cloud rejected the thing too often, it is `Failed`
+        ThingFailed(serial_number: String, reason: String) {
+            description("thing failed")
+            display("thing with serial number {} failed: {}", serial_number, reason)
+        }
+
+        /// The thing did not become `Active` while waiting for it
+        WaitTimedOut(serial_number: String, state: ThingState) {
+            description("timed out waiting for thing")
+            display("thing with serial number {} is still {:?}", serial_number, state)
+        }
+
+        /// The SDK was shut down, please see `HubSDK::shutdown`
+        SdkStopped {
+            description("sdk stopped")
+            display("the SDK is no longer running")
+        }
+
+        /// A thing with this serial number already exists on the Geeny cloud, and
+        /// can't be used. Please see `ExistingThingPolicy`
+        ThingExists(serial_number: String, thing_id: Uuid) {
+            description("thing exists on the Geeny cloud")
+            display("thing with serial number {} already exists on the Geeny cloud as {}",
+                    serial_number, thing_id)
+        }
+
+        /// The thing is still managed by the SDK, and must be unpaired first
+        ThingStillPaired(serial_number: String) {
+            description("thing still paired")
+            display("thing with serial number {} must be unpaired first", serial_number)
+        }
+
+        /// A message payload is larger than accepted, in bytes
+        PayloadTooLarge(limit: u64) {
+            description("payload too large")
+            display("the message payload is larger than {} bytes", limit)
+        }
+
+        /// The outbox of the thing is full, and no more messages are accepted
+        OutboxFull(serial_number: String) {
+            description("outbox full")
+            display("outbox of thing with serial number {} is full", serial_number)
+        }
+
+        /// All IPC workers available for event streams and waits are in use
+        TooManyLongRequests(limit: usize) {
+            description("too many long running requests")
+            display("at most {} event streams and waits are served at a time", limit)
+        }
+    }
+}
+
+/// Classify an error reported by the Geeny API by the HTTP status of the response,
+/// so that authorization and network failures can be handled by kind
+pub(crate) fn api_error(e: gerr::Error) -> Error {
+    let kind = match *e.kind() {
+        gerr::ErrorKind::Reqwest(ref cause) => match cause.status().map(|s| s.as_u16()) {
+            Some(401) | Some(403) => Some(ErrorKind::InvalidCredentials),
+            Some(status) if status >= 500 => Some(ErrorKind::NetworkUnavailable),
+            Some(status) if status >= 400 => Some(ErrorKind::CloudRejected(status)),
+            Some(_) => None,
+
+            // No response was received, e.g. the connection was refused
+            None if cause.is_http() => Some(ErrorKind::NetworkUnavailable),
+            None => None,
+        },
+        _ => None,
+    };
+
+    match kind {
+        Some(kind) => Error::with_chain(e, kind),
+        None => e.into(),
+    }
 }
 
 // Implement `Responder` for `error_chain`'s `Error` type
@@ -64,6 +232,8 @@ impl<'r> Responder<'r> for Error {
     /// in the case of error. Please see [this blog post](https://jamesmunns.com/update/2017/07/22/rocket-plus-error-chain.html)
     /// for more information
     fn respond_to(self, _: &Request) -> ::std::result::Result<Response<'r>, Status> {
+        let (status, code) = status_and_code(self.kind());
+
         // Render the whole error chain to a single string
         let mut rslt = String::new();
         write!(rslt, "Error: {}", self).unwrap();
@@ -77,6 +247,7 @@ impl<'r> Responder<'r> for Error {
         // Create JSON response
         let resp = json!({
             "status": "failure",
+            "code": code,
             "message": rslt,
         }).to_string();
 
@@ -84,10 +255,42 @@ impl<'r> Responder<'r> for Error {
         // successfully created an error response
         Ok(
             Response::build()
-                .status(Status::BadRequest)
+                .status(status)
                 .header(ContentType::JSON)
                 .sized_body(Cursor::new(resp))
                 .finalize(),
         )
     }
 }
+
+/// Map an error kind to the HTTP status and machine readable error code used
+/// in REST responses
+#[cfg(feature = "rest-service")]
+fn status_and_code(kind: &ErrorKind) -> (Status, &'static str) {
+    match *kind {
+        ErrorKind::UnknownSerial(_) => (Status::NotFound, "unknown_serial"),
+        ErrorKind::UnknownThingId(_) => (Status::NotFound, "unknown_thing_id"),
+        ErrorKind::NotLoggedIn => (Status::Unauthorized, "not_logged_in"),
+        ErrorKind::InvalidCredentials => (Status::Unauthorized, "invalid_credentials"),
+        ErrorKind::DuplicateThing(_) => (Status::Conflict, "duplicate_thing"),
+        ErrorKind::ThingStillPaired(_) => (Status::Conflict, "thing_still_paired"),
+        ErrorKind::ThingNotActive(_) => (Status::Conflict, "thing_not_active"),
+        ErrorKind::ThingFailed(..) => (Status::Conflict, "thing_failed"),
+        ErrorKind::ThingExists(..) => (Status::Conflict, "thing_exists"),
+        ErrorKind::InvalidThingRequest(_) => (Status::BadRequest, "invalid_thing_request"),
+        ErrorKind::PayloadTooLarge(_) => (Status::PayloadTooLarge, "payload_too_large"),
+        ErrorKind::OutboxFull(_) => (Status::ServiceUnavailable, "outbox_full"),
+        ErrorKind::TooManyLongRequests(_) => (Status::ServiceUnavailable, "too_many_requests"),
+        ErrorKind::NetworkUnavailable => (Status::ServiceUnavailable, "network_unavailable"),
+        ErrorKind::SdkStopped => (Status::ServiceUnavailable, "sdk_stopped"),
+        ErrorKind::GeenyApi(_) |
+        ErrorKind::CloudRejected(_) => (Status::BadGateway, "cloud_error"),
+        ErrorKind::MissingDirectory(_) |
+        ErrorKind::PermissionDenied(_) |
+        ErrorKind::CorruptElementFile(_) |
+        ErrorKind::CorruptCredentialsFile(_) |
+        ErrorKind::StorageCorrupt(_) |
+        ErrorKind::Mvdb(_) => (Status::InternalServerError, "storage_error"),
+        _ => (Status::BadRequest, "bad_request"),
+    }
+}
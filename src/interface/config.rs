@@ -1,6 +1,9 @@
 use geeny_api::{ThingsApi, ConnectApi};
+use std::collections::HashMap;
 use std::path::PathBuf;
 
+use things_db::{DeliveryOptions, QoS};
+
 /// Configuration structure for a `HubSDK` instance
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct HubSDKConfig {
@@ -17,13 +20,13 @@ pub struct HubSDKConfig {
 
     /// Path to a file to store information regarding the user currently authorized to
     /// use this Hub. This file stores sensitive information such as the username/email
-    /// of the current user, as well as the current API token used to make device management
-    /// requests
+    /// of the current user. The current API token used to make device management
+    /// requests is kept in the `secret_store`
     pub geeny_creds_file: PathBuf,
 
     /// Path to a folder to store certificates used to connect via MQTT. This folder stores
     /// sensitive information, such as private and public key pairs for each device paired
-    /// with the SDK
+    /// with the SDK, as well as the current API token. Please see `secret_store`
     pub mqtt_cert_path: PathBuf,
 
     // TODO: maybe consolodate these into an "mqtt" struct,
@@ -33,6 +36,194 @@ pub struct HubSDKConfig {
 
     /// The MQTT port to connect to all devices, e.g., `8883`
     pub mqtt_port: u16,
+
+    /// The MQTT Quality of Service level used when neither the message nor
+    /// the resource specify one. Defaults to `Level0`
+    #[serde(default)]
+    pub mqtt_qos: QoS,
+
+    /// Whether published messages are retained by the broker when neither the
+    /// message nor the resource specify it. Defaults to `false`
+    #[serde(default)]
+    pub mqtt_retain: bool,
+
+    /// MQTT delivery options for individual resources, keyed by resource URI.
+    /// These are used for subscriptions, as well as for messages published
+    /// to a matching topic
+    #[serde(default)]
+    pub mqtt_resource_options: HashMap<String, DeliveryOptions>,
+
+    /// Limits for the persistent outbox of each thing. Messages waiting to be sent
+    /// to the Geeny cloud are stored like other state, please see `storage`. JSON
+    /// files are kept in an `outbox` folder next to the `element_file`. Messages
+    /// are kept across restarts until they have been published
+    #[serde(default)]
+    pub outbox: OutboxConfig,
+
+    /// What to do when a thing is created, but a thing with the same serial number
+    /// already exists on the Geeny cloud. Defaults to `Adopt`
+    #[serde(default)]
+    pub existing_thing_policy: ExistingThingPolicy,
+
+    /// How secrets stored in `mqtt_cert_path` are protected. Defaults to `File`.
+    /// Other storage may be used with `HubSDK::with_secret_store`
+    #[serde(default)]
+    pub secret_store: SecretStoreConfig,
+
+    /// How often, and how quickly, failed requests to create or update a thing on
+    /// the Geeny cloud are retried
+    #[serde(default)]
+    pub provisioning_retry: RetryConfig,
+
+    /// Where the paired things and the current user are stored. Defaults to `Json`,
+    /// using `element_file` and `geeny_creds_file`
+    #[serde(default)]
+    pub storage: StorageConfig,
+
+    /// Whether the paired things or the current user are reset when they can't be
+    /// read, and no backup can be restored. Otherwise the SDK fails to start with
+    /// `CorruptElementFile` or `CorruptCredentialsFile`. Defaults to `false`
+    #[serde(default)]
+    pub reset_corrupt_storage: bool,
+}
+
+/// Storage for secrets, such as the API token and the private keys of each thing
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum SecretStoreConfig {
+    /// Plaintext files, only readable by the owner
+    File,
+
+    /// Files encrypted with a key derived from the contents of `machine_secret_file`,
+    /// e.g. `/etc/machine-id`. Certificates are decrypted into `runtime_dir` while
+    /// in use, which should reside on a tmpfs such as `/run`. Plaintext secrets
+    /// stored by `File` are encrypted and removed on start
+    Encrypted {
+        machine_secret_file: PathBuf,
+        runtime_dir: PathBuf,
+    },
+}
+
+impl Default for SecretStoreConfig {
+    fn default() -> Self {
+        SecretStoreConfig::File
+    }
+}
+
+/// Storage for the paired things, the current user and the outboxes of things
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum StorageConfig {
+    /// JSON files at `element_file` and `geeny_creds_file`, each replaced as a
+    /// whole when it changes. Files saved by previous versions of the SDK are
+    /// upgraded when opened, the original is kept with a `.v<version>.bak` suffix.
+    /// Backups are kept with `.bak.<n>` suffixes, and unreadable files are moved
+    /// aside with a `.corrupt.<timestamp>` suffix
+    Json,
+
+    /// A SQLite database, which requires the `sqlite-storage` feature. Existing
+    /// `element_file` and `geeny_creds_file` are imported on first start, and
+    /// renamed with an `.imported` suffix.
+    ///
+    /// Each entry of a document is stored as a row of its own, e.g. each paired
+    /// thing, and saving a document only writes the rows which changed, in a
+    /// single transaction. Documents are saved independently of each other, a
+    /// change to the paired things and to the current user is not atomic
+    Sqlite { database_file: PathBuf },
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig::Json
+    }
+}
+
+/// Policy for creating a thing whose serial number already exists on the Geeny cloud
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExistingThingPolicy {
+    /// Reuse the existing thing. The Things API does not provide certificates
+    /// for existing things, so certificates stored by an earlier pairing are
+    /// used. Without them the existing thing is replaced, as with `Replace`
+    Adopt,
+
+    /// Delete the existing thing, and create a new thing with fresh certificates.
+    /// The thing gets a new Geeny Thing ID
+    Replace,
+
+    /// Do not create the thing, it is `Failed`. Existing things are never deleted
+    Fail,
+
+    /// Create an additional thing with the same serial number
+    CreateDuplicate,
+}
+
+impl Default for ExistingThingPolicy {
+    fn default() -> Self {
+        ExistingThingPolicy::Adopt
+    }
+}
+
+/// Configuration of the persistent per-thing message outbox
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct OutboxConfig {
+    /// Maximum number of messages held for a single thing
+    pub max_messages: usize,
+
+    /// Maximum age of a queued message in seconds. Older messages are discarded
+    /// without being sent
+    pub max_age_secs: u64,
+
+    /// What to do when a new message arrives for a full outbox
+    pub eviction: EvictionPolicy,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            max_messages: 1000,
+            max_age_secs: 7 * 24 * 60 * 60, // One week
+            eviction: EvictionPolicy::DropOldest,
+        }
+    }
+}
+
+/// Policy applied when a message is sent to a full outbox
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EvictionPolicy {
+    /// Discard the oldest queued message to make room for the new one
+    DropOldest,
+
+    /// Discard the new message, keeping the queued messages
+    DropNewest,
+
+    /// Refuse the new messages, returning an error to the sender
+    Reject,
+}
+
+/// Retry policy for requests to the Geeny cloud made on behalf of a single thing.
+/// Failed requests are retried with exponential backoff, each delay is randomly
+/// shortened by up to half, so that many things do not retry at the same time
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RetryConfig {
+    /// Delay in seconds before the first retry. Doubled after each failure
+    pub initial_backoff_secs: u64,
+
+    /// Maximum delay in seconds between two attempts
+    pub max_backoff_secs: u64,
+
+    /// Number of consecutive permanent errors after which the thing enters the
+    /// `Failed` state. Only requests rejected by the Geeny cloud with a 4xx status
+    /// count as permanent, network errors and outages never do. Zero retries
+    /// permanent errors forever
+    pub max_permanent_failures: u32,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            initial_backoff_secs: 5,
+            max_backoff_secs: 60 * 60, // One hour
+            max_permanent_failures: 5,
+        }
+    }
 }
 
 impl Default for HubSDKConfig {
@@ -57,6 +248,17 @@ impl Default for HubSDKConfig {
 
             mqtt_host: "mqtt.geeny.io".into(),
             mqtt_port: 8883,
+
+            mqtt_qos: QoS::default(),
+            mqtt_retain: false,
+            mqtt_resource_options: HashMap::new(),
+
+            outbox: OutboxConfig::default(),
+            existing_thing_policy: ExistingThingPolicy::default(),
+            secret_store: SecretStoreConfig::default(),
+            provisioning_retry: RetryConfig::default(),
+            storage: StorageConfig::default(),
+            reset_corrupt_storage: false,
         }
     }
 }
@@ -1,5 +1,6 @@
 mod config;
 mod sdk;
 
-pub use self::config::HubSDKConfig;
+pub use self::config::{EvictionPolicy, ExistingThingPolicy, HubSDKConfig, OutboxConfig,
+                       RetryConfig, SecretStoreConfig, StorageConfig};
 pub use self::sdk::HubSDK;
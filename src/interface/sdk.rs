@@ -1,33 +1,55 @@
-use std::fs::Permissions;
-use std::fs;
-use std::os::unix::fs::PermissionsExt;
-use std::sync::Arc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use geeny_api;
 use log;
-use mvdb::Mvdb;
+use uuid::Uuid;
 
-use auth_manager::{self, ServiceCredentials};
+use auth_manager::{self, AuthEvent, Credentials, ServiceCredentials, AUTH_EVENT_KEY};
+use broadcast::{Broadcast, Subscription};
 use errors::*;
-use interface::config::HubSDKConfig;
-use things_db::{self, PartialThingMessage, ThingDb};
+use interface::config::{HubSDKConfig, StorageConfig};
+use secrets::{self, SecretStore};
+use signal::Signal;
+use storage::{self, StorageRecovery, Store};
+use things_db::{self, CreateThingResult, PartialThingMessage, ThingDb, ThingInfo, ThingMessage,
+                ThingState};
+
+// While waiting for a thing, look it up this often in case it was deleted
+const WAIT_RECHECK_SECS: u64 = 5;
 
 /// Interface handle for a `HubSDK` instance
 #[derive(Clone)]
 pub struct HubSDK {
     config: HubSDKConfig, // Do I need to hold this? Should it be Arc?
-    thing_db_data: Mvdb<ThingDb>,
-    thing_db_handle: Arc<JoinHandle<()>>, // TODO: Not very useful without a way to join
-    auth_mgr_handle: Arc<JoinHandle<()>>, // TODO: Not very useful without a way to join
-    credentials: Mvdb<ServiceCredentials>,
+    thing_db_data: Store<ThingDb>,
+
+    // Handles are taken when joined by `HubSDK::shutdown`
+    thing_db_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+    auth_mgr_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+    signal: Signal,
+
+    credentials: Credentials,
+    messages: Broadcast<ThingMessage>,
+    auth_events: Broadcast<AuthEvent>,
+    states: Broadcast<ThingInfo>,
+
+    // Documents which had to be recovered at startup
+    storage_recoveries: Vec<StorageRecovery>,
 }
 
 impl HubSDK {
     /// Create a new instance of the Geeny Hub SDK. SDK will immediately
     /// begin operation
     ///
+    /// # Panics
+    ///
+    /// Panics if the SDK can not be started, please see `HubSDK::try_new`
+    /// for a fallible version
+    ///
     /// # Example
     ///
     /// ```rust,no_run
@@ -44,17 +66,76 @@ impl HubSDK {
     /// }
     /// ```
     pub fn new(cfg: HubSDKConfig) -> Self {
+        Self::try_new(cfg).expect("Failed to start the Hub SDK")
+    }
+
+    /// Create a new instance of the Geeny Hub SDK, reporting an error if the
+    /// SDK can not be started. SDK will immediately begin operation
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// extern crate hub_sdk;
+    ///
+    /// use hub_sdk::{HubSDK, HubSDKConfig};
+    /// use hub_sdk::errors::ErrorKind;
+    ///
+    /// fn main() {
+    ///     let sdk_cfg = HubSDKConfig::default();
+    ///
+    ///     match HubSDK::try_new(sdk_cfg) {
+    ///         Ok(_hub_sdk) => println!("Running!"),
+    ///         Err(e) => match *e.kind() {
+    ///             ErrorKind::MissingDirectory(ref path) => println!("Please create {:?}", path),
+    ///             ErrorKind::PermissionDenied(ref path) => println!("Can't access {:?}", path),
+    ///             ErrorKind::CorruptElementFile(ref path) => println!("Please restore {:?}", path),
+    ///             _ => println!("Failed to start: {}", e),
+    ///         },
+    ///     }
+    /// }
+    /// ```
+    pub fn try_new(cfg: HubSDKConfig) -> Result<Self> {
         // Create relevant folders before proceeding (otherwise further steps may fail)
-        make_dirs(&cfg).expect("Failed to create required folders");
+        make_dirs(&cfg)?;
 
-        let credentials: Mvdb<ServiceCredentials> =
-            Mvdb::from_file_or_default(&cfg.geeny_creds_file)
-                .expect("Failed to load/create credentials file");
+        let secrets = secrets::from_config(&cfg)?;
+        Self::start(cfg, secrets)
+    }
 
-        // Make sure permissions are correct for credentials file
-        let creds_perm = Permissions::from_mode(0o600);
-        fs::set_permissions(cfg.geeny_creds_file.clone(), creds_perm)
-            .expect("Failed to set credentials file permissions");
+    /// Create a new instance of the Geeny Hub SDK, keeping the API token and the
+    /// certificates of each thing in a custom `SecretStore`, e.g. one backed by
+    /// a TPM or a system keyring. The `secret_store` setting of the configuration
+    /// is ignored. SDK will immediately begin operation
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use std::path::PathBuf;
+    /// use std::sync::Arc;
+    /// use hub_sdk::{FileSecretStore, HubSDK, HubSDKConfig};
+    ///
+    /// let sdk_cfg = HubSDKConfig::default();
+    /// let store = FileSecretStore::new(PathBuf::from("/var/lib/my-hub/secrets"));
+    ///
+    /// let hub_sdk = HubSDK::with_secret_store(sdk_cfg, Arc::new(store))
+    ///     .expect("Failed to start the Hub SDK");
+    /// ```
+    pub fn with_secret_store(cfg: HubSDKConfig, secrets: Arc<SecretStore>) -> Result<Self> {
+        make_dirs(&cfg)?;
+        Self::start(cfg, secrets)
+    }
+
+    fn start(cfg: HubSDKConfig, secrets: Arc<SecretStore>) -> Result<Self> {
+        let creds_file = cfg.geeny_creds_file.clone();
+        let creds_info: Store<ServiceCredentials> = Store::open(
+            &cfg.storage,
+            "credentials",
+            &cfg.geeny_creds_file,
+            cfg.reset_corrupt_storage,
+            || ErrorKind::CorruptCredentialsFile(creds_file.clone()),
+        )?;
+        let creds_recovery = creds_info.recovery();
+        let credentials = Credentials::new(creds_info, secrets.clone())?;
 
         // Create accessors for config data
         let auth_mgr_cfg = cfg.clone();
@@ -62,22 +143,82 @@ impl HubSDK {
         let auth_mgr_auth = credentials.clone();
         let runner_auth = credentials.clone();
 
-        let mut dbr = things_db::ThingDbRunner::new(runner_cfg, runner_auth);
+        let signal = Signal::default();
+        let auth_mgr_signal = signal.clone();
+
+        let auth_events = Broadcast::default();
+        let auth_mgr_events = auth_events.clone();
+
+        let mut dbr =
+            things_db::ThingDbRunner::new(runner_cfg, runner_auth, secrets, signal.clone())?;
         let data = dbr.thing_db_handle();
+        let messages = dbr.message_broadcast();
+        let states = dbr.state_broadcast();
+        let storage_recoveries = creds_recovery.into_iter().chain(data.recovery()).collect();
 
         let auth_mgr = thread::spawn(move || {
-            auth_manager::auth_manager(auth_mgr_cfg, &auth_mgr_auth);
+            auth_manager::auth_manager(
+                auth_mgr_cfg,
+                &auth_mgr_auth,
+                &auth_mgr_events,
+                &auth_mgr_signal,
+            );
         });
         let tdb_run = thread::spawn(move || { dbr.run(); });
 
 
-        Self {
+        Ok(Self {
             config: cfg,
             thing_db_data: data,
-            thing_db_handle: Arc::new(tdb_run),
-            auth_mgr_handle: Arc::new(auth_mgr),
+            thing_db_handle: Arc::new(Mutex::new(Some(tdb_run))),
+            auth_mgr_handle: Arc::new(Mutex::new(Some(auth_mgr))),
+            signal: signal,
             credentials: credentials,
+            messages: messages,
+            auth_events: auth_events,
+            states: states,
+            storage_recoveries: storage_recoveries,
+        })
+    }
+
+    /// Stop the SDK. All MQTT connections are closed, message subscriptions
+    /// end, and the background threads of the SDK are stopped and joined.
+    /// Paired things stay paired, and resume operation when the SDK is
+    /// started again
+    ///
+    /// All clones of this `HubSDK` handle are affected. Calling `shutdown`
+    /// more than once has no further effect
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use hub_sdk::{HubSDK, HubSDKConfig};
+    /// let sdk_cfg = HubSDKConfig::default();
+    /// let hub_sdk = HubSDK::new(sdk_cfg);
+    ///
+    /// hub_sdk.shutdown().expect("Failed to shut down!");
+    /// ```
+    pub fn shutdown(&self) -> Result<()> {
+        self.signal.shutdown();
+
+        for handle in &[&self.thing_db_handle, &self.auth_mgr_handle] {
+            let handle = handle.lock().unwrap_or_else(|e| e.into_inner()).take();
+
+            if let Some(h) = handle {
+                h.join()
+                    .map_err(|_| Error::from("SDK thread panicked before shutdown"))?;
+            }
         }
+
+        self.auth_events.close();
+
+        // Write out the final state of all storage
+        self.credentials.flush()?;
+        self.thing_db_data.access_mut(|_| ())?;
+
+        log::info!("Hub SDK stopped");
+
+        Ok(())
     }
 
     ///////////////////////////////////////////////////////////////////////////
@@ -85,7 +226,9 @@ impl HubSDK {
     ///////////////////////////////////////////////////////////////////////////
 
     // TODO return type
-    /// Check whether a given token is still valid.
+    /// Check whether a given token is still valid. If the Geeny cloud can not
+    /// be reached, an error of kind `ErrorKind::NetworkUnavailable` is returned
+    /// rather than reporting the token as invalid.
     ///
     /// # Example
     ///
@@ -103,18 +246,25 @@ impl HubSDK {
         use geeny_api::models::AuthLoginResponse;
 
         // Do we have a token at all now?
-        let (email, tkn_maybe) = self.credentials.access(|db| {
-            let email = db.username.clone();
-            let tkn_maybe = db.token.clone();
-            (email, tkn_maybe)
-        })?;
+        let email = self.credentials.username()?;
+        let tkn_maybe = self.credentials.token();
 
         let valid = if let Some(tkn) = tkn_maybe {
             let tkn_req = AuthLoginResponse { token: tkn };
 
-            // Does the token check out?
-            // TODO - difference between bad token and network error?
-            self.config.connect_api.check_token(&tkn_req).is_ok()
+            // Does the token check out? Only a rejection by the cloud means
+            // the token is invalid
+            match self.config.connect_api.check_token(&tkn_req) {
+                Ok(_) => true,
+                Err(e) => {
+                    let err = api_error(e);
+                    match *err.kind() {
+                        ErrorKind::InvalidCredentials => false,
+                        ErrorKind::NetworkUnavailable => return Err(err),
+                        _ => false,
+                    }
+                }
+            }
         } else {
             false
         };
@@ -140,6 +290,8 @@ impl HubSDK {
     /// It is HIGHLY RECOMMENDED never to store the user's password, and instead
     /// prompt the user directly whenever a login is necessary.
     ///
+    /// Rejected credentials are reported as `ErrorKind::InvalidCredentials`.
+    ///
     /// # Example
     ///
     /// ```rust,no_run
@@ -158,15 +310,23 @@ impl HubSDK {
             password: password.into(),
         };
 
-        if let Ok(token) = self.config.connect_api.login(&rqst) {
-            self.credentials.access_mut(move |db| {
-                db.username = email.into();
-                db.token = Some(token.token);
-            })?;
-            return Ok(());
-        }
+        let token = self.config.connect_api.login(&rqst).map_err(|e| {
+            let err = api_error(e);
+            match *err.kind() {
+                ErrorKind::NetworkUnavailable => err,
+                _ => Error::with_chain(err, ErrorKind::InvalidCredentials),
+            }
+        })?;
+
+        self.credentials.login(email, token.token)?;
+        self.signal.wake();
 
-        bail!("failure")
+        self.auth_events.publish(
+            AUTH_EVENT_KEY,
+            &AuthEvent::LoggedIn { username: email.into() },
+        );
+
+        Ok(())
     }
 
     /// Logout of the Geeny API. No further API operations will be possible
@@ -185,21 +345,92 @@ impl HubSDK {
         // TODO: We probably need to do some more stuff on logout, like:
         //   - Stopping the auth manager
         //   - Maybe offer to delete all devices?
-        self.credentials.access_mut(move |db| {
-            db.username = "".into();
-            db.token = None;
-        })?;
+        self.credentials.logout()?;
+        self.signal.wake();
 
         self.thing_db_data
             .access_mut(|db| db.unpair_all())?;
 
+        self.auth_events.publish(AUTH_EVENT_KEY, &AuthEvent::LoggedOut);
+
         Ok(())
     }
 
+    /// Subscribe to changes of the login state, such as the token being
+    /// refreshed or expiring. This may be used to prompt the user to log in
+    /// again, instead of polling `HubSDK::check_token`
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use hub_sdk::{AuthEvent, HubSDK, HubSDKConfig};
+    /// let sdk_cfg = HubSDKConfig::default();
+    /// let hub_sdk = HubSDK::new(sdk_cfg);
+    ///
+    /// for event in hub_sdk.subscribe_auth_events() {
+    ///     if event == AuthEvent::TokenExpired {
+    ///         println!("Please log in again");
+    ///     }
+    /// }
+    /// ```
+    pub fn subscribe_auth_events(&self) -> Subscription<AuthEvent> {
+        self.auth_events.subscribe(None)
+    }
+
+    /// Subscribe to changes of the state of all things managed by the SDK, such
+    /// as a thing becoming `Active`, or receiving a new Geeny Thing ID when its
+    /// certificates are rotated
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use hub_sdk::{HubSDK, HubSDKConfig};
+    /// let sdk_cfg = HubSDKConfig::default();
+    /// let hub_sdk = HubSDK::new(sdk_cfg);
+    ///
+    /// for info in hub_sdk.subscribe_thing_states() {
+    ///     if let Some(previous) = info.previous_thing_id {
+    ///         println!("s/n: {}, {} is now {:?}", info.serial_number, previous, info.thing_id);
+    ///     }
+    /// }
+    /// ```
+    pub fn subscribe_thing_states(&self) -> Subscription<ThingInfo> {
+        self.states.subscribe(None)
+    }
+
+    /// Storage which could not be read when the SDK was started, e.g. because
+    /// the element file was truncated by a power loss. Unreadable files are
+    /// quarantined, and restored from the most recent backup that can be read.
+    /// If there is none and `HubSDKConfig::reset_corrupt_storage` is set, paired
+    /// things or the current login are lost, and applications should alert the user
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use hub_sdk::{HubSDK, HubSDKConfig};
+    /// let sdk_cfg = HubSDKConfig::default();
+    /// let hub_sdk = HubSDK::new(sdk_cfg);
+    ///
+    /// for recovery in hub_sdk.storage_recoveries() {
+    ///     match recovery.restored_from {
+    ///         Some(backup) => println!("Restored {} from {}", recovery.document, backup),
+    ///         None => println!("Lost {}: {}", recovery.document, recovery.reason),
+    ///     }
+    /// }
+    /// ```
+    pub fn storage_recoveries(&self) -> Vec<StorageRecovery> {
+        self.storage_recoveries.clone()
+    }
+
     ///////////////////////////////////////////////////////////////////////////
     // Things
     ///////////////////////////////////////////////////////////////////////////
-    /// Create a new thing on the Geeny cloud
+    /// Create a new thing on the Geeny cloud. Creating a thing with a serial
+    /// number already managed by the SDK returns an error of kind
+    /// `ErrorKind::DuplicateThing`, unless that thing is `Failed`. A `Failed`
+    /// thing is created again. Requests without a serial number, name or thing
+    /// type return an error of kind `ErrorKind::InvalidThingRequest`. The thing
+    /// is created in the background, please see `HubSDK::wait_for_active`
     ///
     /// # Example
     ///
@@ -222,8 +453,183 @@ impl HubSDK {
     /// ```
     pub fn create_thing(&self, request: geeny_api::models::ThingRequest) -> Result<()> {
         self.thing_db_data
-            .access_mut(|db| db.add_thing(request))?
-            .chain_err(|| "Failed to add thing")
+            .access_mut(|db| db.add_thing(request))??;
+
+        self.signal.wake();
+        Ok(())
+    }
+
+    /// Create several things on the Geeny cloud at once. Each request is
+    /// accepted, or refused as a duplicate or as invalid on its own; the
+    /// results are in the order of the requests
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// extern crate uuid;
+    /// use uuid::Uuid;
+    /// use hub_sdk::{HubSDK, HubSDKConfig};
+    /// use hub_sdk::geeny_api::models::ThingRequest;
+    /// use hub_sdk::services::CreateThingStatus;
+    /// let sdk_cfg = HubSDKConfig::default();
+    /// let hub_sdk = HubSDK::new(sdk_cfg);
+    ///
+    /// let new_things = (0..100)
+    ///     .map(|n| ThingRequest {
+    ///         name: format!("Demo Sensor {}", n),
+    ///         serial_number: format!("SENSOR-{:04}", n),
+    ///         thing_type: Uuid::from("2CB7F29A-527B-11E7-B114-B2F933D5FE66"),
+    ///     })
+    ///     .collect();
+    ///
+    /// let results = hub_sdk.create_things(new_things)
+    ///     .expect("Failed to create new things!");
+    ///
+    /// for result in results {
+    ///     if result.status != CreateThingStatus::Accepted {
+    ///         println!("{} refused: {:?}", result.serial_number, result.status);
+    ///     }
+    /// }
+    /// ```
+    pub fn create_things(
+        &self,
+        requests: Vec<geeny_api::models::ThingRequest>,
+    ) -> Result<Vec<CreateThingResult>> {
+        let results = self.thing_db_data
+            .access_mut(|db| db.add_things(requests))??;
+
+        self.signal.wake();
+        Ok(results)
+    }
+
+    /// List all things currently managed by the SDK, ordered by serial number
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use hub_sdk::{HubSDK, HubSDKConfig};
+    /// let sdk_cfg = HubSDKConfig::default();
+    /// let hub_sdk = HubSDK::new(sdk_cfg);
+    ///
+    /// let things = hub_sdk.list_things()
+    ///     .expect("Failed to list things!");
+    ///
+    /// for thing in things {
+    ///     println!("s/n: {}, state: {:?}", thing.serial_number, thing.state);
+    /// }
+    /// ```
+    pub fn list_things(&self) -> Result<Vec<ThingInfo>> {
+        self.thing_db_data.access(|db| db.list())
+    }
+
+    /// Obtain the current state of a thing managed by the SDK. If the thing
+    /// does not become `Active`, `last_failure` describes what went wrong
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use hub_sdk::{HubSDK, HubSDKConfig};
+    /// let sdk_cfg = HubSDKConfig::default();
+    /// let hub_sdk = HubSDK::new(sdk_cfg);
+    ///
+    /// let thing = hub_sdk.get_thing("ABC123456")
+    ///     .expect("Failed to get thing!");
+    ///
+    /// println!("Geeny ID: {:?}, MQTT: {}", thing.thing_id, thing.mqtt_client_started);
+    ///
+    /// if let Some(failure) = thing.last_failure {
+    ///     println!("Failed {} times: {}", failure.attempts, failure.reason);
+    /// }
+    /// ```
+    pub fn get_thing(&self, serial: &str) -> Result<ThingInfo> {
+        self.thing_db_data.access(|db| db.info(serial))?
+    }
+
+    /// Wait for up to `timeout` until a thing is `Active`, e.g. after
+    /// `HubSDK::create_thing`, and obtain its state
+    ///
+    /// # Errors
+    ///
+    /// * `ErrorKind::ThingFailed` with the provisioning error, if the Geeny cloud
+    ///   rejected the thing too often
+    /// * `ErrorKind::WaitTimedOut` if the thing is still being provisioned. Please
+    ///   see `ThingInfo::last_failure` for why that is
+    /// * `ErrorKind::UnknownSerial` if the thing is not managed by the SDK
+    /// * `ErrorKind::SdkStopped` if the SDK is shut down while waiting
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use std::time::Duration;
+    /// use hub_sdk::{HubSDK, HubSDKConfig};
+    /// let sdk_cfg = HubSDKConfig::default();
+    /// let hub_sdk = HubSDK::new(sdk_cfg);
+    ///
+    /// let thing = hub_sdk.wait_for_active("ABC123456", Duration::from_secs(60))
+    ///     .expect("Thing did not become active!");
+    ///
+    /// println!("Geeny ID: {:?}, MQTT: {}", thing.thing_id, thing.mqtt_client_started);
+    /// ```
+    pub fn wait_for_active(&self, serial: &str, timeout: Duration) -> Result<ThingInfo> {
+        let deadline = Instant::now() + timeout;
+
+        // Subscribe before looking at the thing, so that no change is missed
+        let states = self.states.subscribe(Some(serial.into()));
+        let mut info = self.get_thing(serial)?;
+
+        loop {
+            match info.state {
+                ThingState::Active => return Ok(info),
+                ThingState::Failed => {
+                    let reason = info.last_failure
+                        .map(|failure| failure.reason)
+                        .unwrap_or_else(|| "unknown error".into());
+                    bail!(ErrorKind::ThingFailed(serial.into(), reason));
+                }
+                _ => {}
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                bail!(ErrorKind::WaitTimedOut(serial.into(), info.state));
+            }
+
+            let wait = (deadline - now).min(Duration::from_secs(WAIT_RECHECK_SECS));
+            info = match states.recv_timeout(wait) {
+                Ok(changed) => changed,
+                Err(RecvTimeoutError::Timeout) => self.get_thing(serial)?,
+                Err(RecvTimeoutError::Disconnected) => bail!(ErrorKind::SdkStopped),
+            };
+        }
+    }
+
+    /// Obtain the current state of a thing managed by the SDK, by its Geeny
+    /// Thing ID. Things can only be found by their ID once they have been
+    /// created on the Geeny cloud
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// extern crate hub_sdk;
+    /// extern crate uuid;
+    ///
+    /// use hub_sdk::{HubSDK, HubSDKConfig};
+    /// use uuid::Uuid;
+    ///
+    /// fn main() {
+    ///     let sdk_cfg = HubSDKConfig::default();
+    ///     let hub_sdk = HubSDK::new(sdk_cfg);
+    ///
+    ///     let id = Uuid::parse_str("0A6CA1B8-2F45-4E24-9E22-1A0F3A0C83C4").unwrap();
+    ///     let thing = hub_sdk.get_thing_by_id(&id)
+    ///         .expect("Failed to get thing!");
+    ///
+    ///     println!("s/n: {}, state: {:?}", thing.serial_number, thing.state);
+    /// }
+    /// ```
+    pub fn get_thing_by_id(&self, thing_id: &Uuid) -> Result<ThingInfo> {
+        self.thing_db_data
+            .access(|db| db.serial_for_id(thing_id).and_then(|s| db.info(&s)))?
     }
 
     /// Delete a thing from the Geeny cloud. The thing must not be
@@ -244,14 +650,18 @@ impl HubSDK {
             .access_mut(|db| db.contains_serial(serial))?;
 
         if exists {
-            bail!("Device must be unpaired before deletion")
+            bail!(ErrorKind::ThingStillPaired(serial.into()))
         }
 
         let token = self.credentials
-            .access(|auth| auth.token.clone())?
-            .ok_or_else(|| Error::from("No token, cannot delete. Please log in"))?;
-
-        let thing = match self.config.api.get_thing_by_serial(&token, &serial)? {
+            .token()
+            .ok_or_else(|| Error::from(ErrorKind::NotLoggedIn))?;
+
+        let thing = match self.config
+            .api
+            .get_thing_by_serial(&token, serial)
+            .map_err(api_error)?
+        {
             Some(t) => t,
             None => {
                 // If there is no matching device, still report okay
@@ -259,7 +669,10 @@ impl HubSDK {
             }
         };
 
-        let _ = self.config.api.delete_thing(&token, &thing.id)?;
+        let _ = self.config
+            .api
+            .delete_thing(&token, &thing.id)
+            .map_err(api_error)?;
 
         Ok(())
     }
@@ -288,8 +701,73 @@ impl HubSDK {
         Ok(())
     }
 
+    /// Unpair a thing that is managed by the SDK, by its Geeny Thing ID.
+    /// Unlike `HubSDK::unpair_thing_by_serial`, unpairing an unknown thing
+    /// causes an error, as the ID may not be known to the SDK yet
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// extern crate hub_sdk;
+    /// extern crate uuid;
+    ///
+    /// use hub_sdk::{HubSDK, HubSDKConfig};
+    /// use uuid::Uuid;
+    ///
+    /// fn main() {
+    ///     let sdk_cfg = HubSDKConfig::default();
+    ///     let hub_sdk = HubSDK::new(sdk_cfg);
+    ///
+    ///     let id = Uuid::parse_str("0A6CA1B8-2F45-4E24-9E22-1A0F3A0C83C4").unwrap();
+    ///     hub_sdk.unpair_thing_by_id(&id)
+    ///         .expect("Failed to unpair thing!");
+    /// }
+    /// ```
+    pub fn unpair_thing_by_id(&self, thing_id: &Uuid) -> Result<()> {
+        self.thing_db_data
+            .access_mut(|db| db.serial_for_id(thing_id).and_then(|s| db.unpair(&s)))?
+    }
+
+    /// Obtain fresh certificates for an active thing, e.g. when its certificate
+    /// is about to expire. The expiry of the current certificate is reported by
+    /// `HubSDK::get_thing`
+    ///
+    /// The Things API only hands out certificates when a thing is created, so the
+    /// thing is replaced on the Geeny cloud by a new thing with the same serial
+    /// number, and receives a new Geeny Thing ID. The new ID is published to
+    /// `HubSDK::subscribe_thing_states`, and the old one is reported as
+    /// `ThingInfo::previous_thing_id`. The old thing is deleted from the Geeny cloud
+    /// once the new thing has been saved. The MQTT connection is closed until the
+    /// new certificates have been obtained. Messages sent in the meantime are kept
+    /// in the outbox, and sent once the thing is connected again.
+    ///
+    /// Rotating the certificates of a thing which is not `Active` returns an error
+    /// of kind `ErrorKind::ThingNotActive`
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use hub_sdk::{HubSDK, HubSDKConfig};
+    /// let sdk_cfg = HubSDKConfig::default();
+    /// let hub_sdk = HubSDK::new(sdk_cfg);
+    ///
+    /// hub_sdk.rotate_certificates("ABC123456")
+    ///     .expect("Failed to rotate certificates!");
+    /// ```
+    pub fn rotate_certificates(&self, serial: &str) -> Result<()> {
+        self.thing_db_data
+            .access_mut(|db| db.rotate_certificates(serial))??;
+
+        self.signal.wake();
+        Ok(())
+    }
+
     /// Send messages to the Geeny cloud on behalf of a thing
     ///
+    /// Messages are stored in the persistent outbox of the thing, and are sent in
+    /// order once the thing is active and connected. Queued messages are kept
+    /// across restarts, within the limits set by `HubSDKConfig::outbox`
+    ///
     /// # Example
     ///
     /// ```rust,no_run
@@ -303,10 +781,12 @@ impl HubSDK {
     ///     PartialThingMessage {
     ///         topic: "demo/send/path".into(),
     ///         msg: "demonstration message".into(),
+    ///         ..Default::default()
     ///     },
     ///     PartialThingMessage {
     ///         topic: "demo/other/path".into(),
     ///         msg: "second demonstration message".into(),
+    ///         ..Default::default()
     ///     },
     /// );
     ///
@@ -317,6 +797,51 @@ impl HubSDK {
         self.thing_db_data
             .access_mut(|db| db.hub_tx(serial, messages))??;
 
+        self.signal.wake();
+        Ok(())
+    }
+
+    /// Send messages to the Geeny cloud on behalf of a thing, by its Geeny
+    /// Thing ID. Please see `HubSDK::send_messages` for further details
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// extern crate hub_sdk;
+    /// extern crate uuid;
+    ///
+    /// use hub_sdk::{HubSDK, HubSDKConfig};
+    /// use hub_sdk::services::PartialThingMessage;
+    /// use uuid::Uuid;
+    ///
+    /// fn main() {
+    ///     let sdk_cfg = HubSDKConfig::default();
+    ///     let hub_sdk = HubSDK::new(sdk_cfg);
+    ///
+    ///     let messages = vec!(
+    ///         PartialThingMessage {
+    ///             topic: "demo/send/path".into(),
+    ///             msg: "demonstration message".into(),
+    ///             ..Default::default()
+    ///         },
+    ///     );
+    ///
+    ///     let id = Uuid::parse_str("0A6CA1B8-2F45-4E24-9E22-1A0F3A0C83C4").unwrap();
+    ///     hub_sdk.send_messages_by_id(&id, &messages)
+    ///         .expect("Failed to send messages!");
+    /// }
+    /// ```
+    pub fn send_messages_by_id(
+        &self,
+        thing_id: &Uuid,
+        messages: &[PartialThingMessage],
+    ) -> Result<()> {
+        self.thing_db_data.access_mut(|db| {
+            let serial = db.serial_for_id(thing_id)?;
+            db.hub_tx(&serial, messages)
+        })??;
+
+        self.signal.wake();
         Ok(())
     }
 
@@ -333,36 +858,155 @@ impl HubSDK {
     ///     .expect("Failed to receive messsages!");
     ///
     /// for msg in messages {
-    ///     println!("topic: >>{}<<, message: >>{}<<", msg.topic, msg.msg);
+    ///     println!(
+    ///         "topic: >>{}<<, message: >>{}<<",
+    ///         msg.topic,
+    ///         String::from_utf8_lossy(&msg.msg)
+    ///     );
     /// }
     /// ```
     pub fn receive_messages(&self, serial: &str) -> Result<Vec<PartialThingMessage>> {
         self.thing_db_data.access_mut(|db| db.hub_rx(serial))?
     }
+
+    /// Obtain any messages sent from the Geeny cloud to a given thing, by its
+    /// Geeny Thing ID
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// extern crate hub_sdk;
+    /// extern crate uuid;
+    ///
+    /// use hub_sdk::{HubSDK, HubSDKConfig};
+    /// use uuid::Uuid;
+    ///
+    /// fn main() {
+    ///     let sdk_cfg = HubSDKConfig::default();
+    ///     let hub_sdk = HubSDK::new(sdk_cfg);
+    ///
+    ///     let id = Uuid::parse_str("0A6CA1B8-2F45-4E24-9E22-1A0F3A0C83C4").unwrap();
+    ///     let messages = hub_sdk.receive_messages_by_id(&id)
+    ///         .expect("Failed to receive messsages!");
+    ///
+    ///     println!("{} messages", messages.len());
+    /// }
+    /// ```
+    pub fn receive_messages_by_id(&self, thing_id: &Uuid) -> Result<Vec<PartialThingMessage>> {
+        self.thing_db_data.access_mut(|db| {
+            let serial = db.serial_for_id(thing_id)?;
+            db.hub_rx(&serial)
+        })?
+    }
+
+    /// Subscribe to messages sent from the Geeny cloud to a given thing. Messages
+    /// are delivered as soon as they arrive, without polling
+    ///
+    /// Messages are also kept for `HubSDK::receive_messages`, subscribing does
+    /// not take them away from pollers. A subscriber that falls more than 1024
+    /// messages behind misses further messages
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use hub_sdk::{HubSDK, HubSDKConfig};
+    /// let sdk_cfg = HubSDKConfig::default();
+    /// let hub_sdk = HubSDK::new(sdk_cfg);
+    ///
+    /// let subscription = hub_sdk.subscribe_messages("ABC123456")
+    ///     .expect("Failed to subscribe!");
+    ///
+    /// // Blocks until the next message arrives
+    /// for message in subscription {
+    ///     println!("topic: >>{}<<, {} bytes", message.msg.topic, message.msg.msg.len());
+    /// }
+    /// ```
+    pub fn subscribe_messages(&self, serial: &str) -> Result<Subscription<ThingMessage>> {
+        let exists = self.thing_db_data
+            .access(|db| db.contains_serial(serial))?;
+
+        if !exists {
+            bail!(ErrorKind::UnknownSerial(serial.into()));
+        }
+
+        Ok(self.messages.subscribe(Some(serial.into())))
+    }
+
+    /// Subscribe to messages sent from the Geeny cloud to any thing managed
+    /// by the SDK
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use hub_sdk::{HubSDK, HubSDKConfig};
+    /// let sdk_cfg = HubSDKConfig::default();
+    /// let hub_sdk = HubSDK::new(sdk_cfg);
+    ///
+    /// for message in hub_sdk.subscribe_all_messages() {
+    ///     println!("s/n: {}, topic: >>{}<<", message.serial_number, message.msg.topic);
+    /// }
+    /// ```
+    pub fn subscribe_all_messages(&self) -> Subscription<ThingMessage> {
+        self.messages.subscribe(None)
+    }
+
+    /// Register a callback to be called for each message sent from the Geeny
+    /// cloud to a given thing, or to all things if no serial number is given.
+    /// The callback is called on a dedicated thread
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use hub_sdk::{HubSDK, HubSDKConfig};
+    /// let sdk_cfg = HubSDKConfig::default();
+    /// let hub_sdk = HubSDK::new(sdk_cfg);
+    ///
+    /// hub_sdk.on_message(Some("ABC123456"), |message| {
+    ///     println!("topic: >>{}<<", message.msg.topic);
+    /// }).expect("Failed to subscribe!");
+    /// ```
+    pub fn on_message<F>(&self, serial: Option<&str>, callback: F) -> Result<JoinHandle<()>>
+    where
+        F: Fn(ThingMessage) + Send + 'static,
+    {
+        let subscription = match serial {
+            Some(serial) => self.subscribe_messages(serial)?,
+            None => self.subscribe_all_messages(),
+        };
+
+        Ok(thread::spawn(move || for message in subscription {
+            callback(message);
+        }))
+    }
 }
 
 fn make_dirs(cfg: &HubSDKConfig) -> Result<()> {
-    let folder_perms = Permissions::from_mode(0o755);
-    let paths = vec![
+    let mut paths = vec![
         // Get the folder the element file resides in
         cfg.element_file
             .parent()
-            .ok_or_else(|| Error::from("bad element path spec"))?,
+            .ok_or_else(|| ErrorKind::MissingDirectory(cfg.element_file.clone()))?,
 
         // Get the folder the credentials file resides in
         cfg.geeny_creds_file
             .parent()
-            .ok_or_else(|| Error::from("bad credentials path spec"))?,
+            .ok_or_else(|| ErrorKind::MissingDirectory(cfg.geeny_creds_file.clone()))?,
 
         // The folder for MQTT certificates
         &cfg.mqtt_cert_path,
     ];
 
+    // The folder the database resides in
+    if let StorageConfig::Sqlite { ref database_file } = cfg.storage {
+        paths.push(
+            database_file
+                .parent()
+                .ok_or_else(|| ErrorKind::MissingDirectory(database_file.clone()))?,
+        );
+    }
+
     for path in paths {
-        fs::create_dir_all(path)
-            .chain_err(|| format!("failed to create certificate directory {:?}", path))?;
-        fs::set_permissions(path, folder_perms.clone())
-            .chain_err(|| "Couldn't set permissions")?;
+        storage::create_dir(path, 0o755)?;
     }
 
 
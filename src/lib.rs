@@ -7,6 +7,9 @@
 
 #![feature(use_extern_macros)]
 
+// Required by the `error_chain!` invocation in `errors`
+#![recursion_limit = "256"]
+
 #![cfg_attr(feature = "rest-service", feature(plugin, custom_derive))]
 #![cfg_attr(feature = "rest-service", plugin(rocket_codegen))]
 
@@ -126,10 +129,15 @@ extern crate log;
 #[macro_use]
 extern crate error_chain;
 
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 extern crate rumqtt;
 extern crate uuid;
+extern crate base64;
+extern crate ring;
+extern crate openssl;
 
 #[cfg(feature = "rest-service")]
 #[macro_use]
@@ -139,17 +147,23 @@ extern crate rocket_contrib;
 extern crate rocket;
 
 
-// TODO DI-245
-//   * Move to sqlite3 or diesel
-//   * OR if still with mvdb, how do we handle poisoned mutexes (Antidote)
+// Used to load the configuration of the service. State is kept in a `storage::Store`
 extern crate mvdb;
 
+#[cfg(feature = "sqlite-storage")]
+extern crate rusqlite;
+
 // Re-export
 pub extern crate geeny_api;
 
 mod interface;
 
-pub use self::interface::{HubSDK, HubSDKConfig};
+pub use self::interface::{EvictionPolicy, ExistingThingPolicy, HubSDK, HubSDKConfig,
+                          OutboxConfig, RetryConfig, SecretStoreConfig, StorageConfig};
+pub use self::secrets::{EncryptedFileSecretStore, FileSecretStore, SecretStore};
+pub use self::broadcast::Subscription;
+pub use self::auth_manager::AuthEvent;
+pub use self::storage::StorageRecovery;
 pub mod errors;
 
 // Used by bin crates, or by external services that consume the
@@ -157,4 +171,8 @@ pub mod errors;
 pub mod services;
 
 mod auth_manager;
+mod broadcast;
+mod secrets;
+mod signal;
+mod storage;
 mod things_db;
@@ -0,0 +1,196 @@
+// Copyright 2017 Telefónica Germany Next GmbH. See the COPYRIGHT file at
+// the top-level directory of this distribution
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::{Path, PathBuf};
+
+use ring::{aead, digest, hkdf, hmac};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use log;
+
+use errors::*;
+use storage;
+use super::{certificate_owner, check_key, list_files, read_file, remove_file, write_file,
+            SecretStore, TOKEN_KEY};
+
+// Inputs to the key derivation. Changing these makes all stored secrets unreadable
+const KEY_SALT: &[u8] = b"geeny-hub-sdk secret store";
+const KEY_INFO: &[u8] = b"secret store key v1";
+
+static ALGORITHM: &aead::Algorithm = &aead::CHACHA20_POLY1305;
+
+const SEALED_SUFFIX: &str = ".sealed";
+
+/// Stores each secret as a file encrypted with ChaCha20-Poly1305, using a key
+/// derived from a machine secret such as `/etc/machine-id`. Stored secrets can
+/// not be read on another machine, or by copying the storage folder.
+///
+/// Secrets requested as a file with `SecretStore::file_path` are decrypted into
+/// a runtime folder, which should reside on a tmpfs such as `/run`.
+///
+/// Plaintext secrets left in the folder by a `FileSecretStore`, i.e. the token and
+/// the certificates of each thing, are encrypted and removed when the store is opened
+pub struct EncryptedFileSecretStore {
+    dir: PathBuf,
+    runtime_dir: PathBuf,
+    sealing_key: aead::SealingKey,
+    opening_key: aead::OpeningKey,
+    rng: SystemRandom,
+}
+
+impl EncryptedFileSecretStore {
+    /// Store encrypted secrets in `dir`, using a key derived from the contents of
+    /// `machine_secret_file`. Both `dir` and `runtime_dir` are created if necessary
+    pub fn new(dir: PathBuf, machine_secret_file: &Path, runtime_dir: PathBuf) -> Result<Self> {
+        let machine_secret = read_file(machine_secret_file)?.unwrap_or_default();
+        if machine_secret.iter().all(|b| (*b as char).is_whitespace()) {
+            bail!("Missing or empty machine secret {:?}", machine_secret_file);
+        }
+
+        let salt = hmac::SigningKey::new(&digest::SHA256, KEY_SALT);
+        let mut key = [0u8; 32];
+        hkdf::extract_and_expand(&salt, &machine_secret, KEY_INFO, &mut key);
+
+        let sealing_key = aead::SealingKey::new(ALGORITHM, &key)
+            .chain_err(|| "Failed to create secret store key")?;
+        let opening_key = aead::OpeningKey::new(ALGORITHM, &key)
+            .chain_err(|| "Failed to create secret store key")?;
+
+        storage::create_dir(&dir, 0o700)?;
+        storage::create_dir(&runtime_dir, 0o700)?;
+
+        let store = EncryptedFileSecretStore {
+            dir: dir,
+            runtime_dir: runtime_dir,
+            sealing_key: sealing_key,
+            opening_key: opening_key,
+            rng: SystemRandom::new(),
+        };
+
+        store.import_plaintext()?;
+        Ok(store)
+    }
+
+    /// Encrypt the secrets stored as plaintext files in the same folder, and
+    /// remove the plaintext files. Plaintext files are more recent than sealed
+    /// ones, as only a `FileSecretStore` writes them
+    fn import_plaintext(&self) -> Result<()> {
+        let plaintext = list_files(&self.dir)?
+            .into_iter()
+            .filter(|name| name == TOKEN_KEY || certificate_owner(name).is_some());
+
+        for key in plaintext {
+            let path = self.dir.join(&key);
+            let secret = match read_file(&path)? {
+                Some(secret) => secret,
+                None => continue,
+            };
+
+            log::info!("Encrypting plaintext secret {:?}", path);
+            self.put(&key, &secret)
+                .chain_err(|| format!("Failed to encrypt plaintext secret {:?}", path))?;
+
+            // Only removed once the sealed secret has been written
+            remove_file(&path)?;
+        }
+
+        Ok(())
+    }
+
+    fn sealed_file(&self, key: &str) -> Result<PathBuf> {
+        check_key(key)?;
+        Ok(self.dir.join(format!("{}{}", key, SEALED_SUFFIX)))
+    }
+
+    fn runtime_file(&self, key: &str) -> Result<PathBuf> {
+        check_key(key)?;
+        Ok(self.runtime_dir.join(key))
+    }
+}
+
+impl SecretStore for EncryptedFileSecretStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.sealed_file(key)?;
+        let contents = match read_file(&path)? {
+            Some(contents) => contents,
+            None => return Ok(None),
+        };
+
+        let nonce_len = ALGORITHM.nonce_len();
+        if contents.len() < nonce_len {
+            bail!(ErrorKind::StorageCorrupt(path));
+        }
+
+        // The file consists of the nonce, followed by the sealed secret. The key
+        // is authenticated as well, so files can't be swapped
+        let (nonce, sealed) = contents.split_at(nonce_len);
+        let mut sealed = sealed.to_vec();
+
+        let secret = aead::open_in_place(&self.opening_key, nonce, key.as_bytes(), 0, &mut sealed)
+            .map_err(|_| Error::from(ErrorKind::StorageCorrupt(path.clone())))?;
+
+        Ok(Some(secret.to_vec()))
+    }
+
+    fn put(&self, key: &str, secret: &[u8]) -> Result<()> {
+        let path = self.sealed_file(key)?;
+
+        // Sealing uses a fresh nonce, so compare the plaintext to avoid needless writes
+        if let Ok(Some(ref current)) = self.get(key) {
+            if &current[..] == secret {
+                return Ok(());
+            }
+        }
+
+        let mut nonce = vec![0u8; ALGORITHM.nonce_len()];
+        self.rng
+            .fill(&mut nonce)
+            .chain_err(|| "Failed to generate nonce")?;
+
+        let tag_len = ALGORITHM.tag_len();
+        let mut in_out = secret.to_vec();
+        in_out.extend(vec![0u8; tag_len]);
+
+        let sealed_len = aead::seal_in_place(
+            &self.sealing_key,
+            &nonce,
+            key.as_bytes(),
+            &mut in_out,
+            tag_len,
+        ).chain_err(|| "Failed to encrypt secret")?;
+
+        let mut contents = nonce;
+        contents.extend_from_slice(&in_out[..sealed_len]);
+        write_file(&path, &contents)?;
+
+        // Any decrypted copy is now stale
+        remove_file(&self.runtime_file(key)?)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        remove_file(&self.runtime_file(key)?)?;
+        remove_file(&self.sealed_file(key)?)
+    }
+
+    fn file_path(&self, key: &str) -> Result<PathBuf> {
+        let secret = self.get(key)?
+            .ok_or_else(|| Error::from(format!("No secret stored for {}", key)))?;
+
+        let path = self.runtime_file(key)?;
+        write_file(&path, &secret)?;
+
+        Ok(path)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        Ok(list_files(&self.dir)?
+            .into_iter()
+            .filter(|name| name.ends_with(SEALED_SUFFIX))
+            .map(|name| name[..name.len() - SEALED_SUFFIX.len()].to_string())
+            .collect())
+    }
+}
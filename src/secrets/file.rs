@@ -0,0 +1,55 @@
+// Copyright 2017 Telefónica Germany Next GmbH. See the COPYRIGHT file at
+// the top-level directory of this distribution
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::PathBuf;
+
+use errors::*;
+use super::{check_key, list_files, read_file, remove_file, write_file, SecretStore};
+
+/// Stores each secret as a plaintext file, only readable by the owner
+pub struct FileSecretStore {
+    dir: PathBuf,
+}
+
+impl FileSecretStore {
+    /// Store secrets in the given folder, which must already exist
+    pub fn new(dir: PathBuf) -> Self {
+        FileSecretStore { dir: dir }
+    }
+
+    fn file_for(&self, key: &str) -> Result<PathBuf> {
+        check_key(key)?;
+        Ok(self.dir.join(key))
+    }
+}
+
+impl SecretStore for FileSecretStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        read_file(&self.file_for(key)?)
+    }
+
+    fn put(&self, key: &str, secret: &[u8]) -> Result<()> {
+        write_file(&self.file_for(key)?, secret)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        remove_file(&self.file_for(key)?)
+    }
+
+    fn file_path(&self, key: &str) -> Result<PathBuf> {
+        let path = self.file_for(key)?;
+        if !path.is_file() {
+            bail!("No secret stored for {}", key);
+        }
+
+        Ok(path)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        list_files(&self.dir)
+    }
+}
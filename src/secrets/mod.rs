@@ -0,0 +1,141 @@
+// Copyright 2017 Telefónica Germany Next GmbH. See the COPYRIGHT file at
+// the top-level directory of this distribution
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Storage of secrets, such as the Geeny API token and the certificates of each thing
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use uuid::Uuid;
+
+use errors::*;
+use interface::{HubSDKConfig, SecretStoreConfig};
+use storage::{self, read_file, write_file};
+
+// Plaintext secrets, stored as files
+mod file;
+
+// Secrets encrypted with a key derived from a machine secret
+mod encrypted;
+
+pub use self::encrypted::EncryptedFileSecretStore;
+pub use self::file::FileSecretStore;
+
+/// Key of the Geeny API token
+pub const TOKEN_KEY: &str = "token";
+
+/// Storage for the secrets used by the SDK. Implement this trait to keep secrets
+/// in a keyring, TPM or HSM, and pass it to `HubSDK::with_secret_store`.
+///
+/// Keys are file name safe ASCII strings, e.g. `token` for the Geeny API token, or
+/// `<thing id>.ca.crt`, `<thing id>.crt` and `<thing id>.key` for the certificates
+/// of a thing
+pub trait SecretStore: Send + Sync {
+    /// Read a secret. Returns `None` if no secret is stored under `key`
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Store a secret, replacing any secret previously stored under `key`
+    fn put(&self, key: &str, secret: &[u8]) -> Result<()>;
+
+    /// Remove a secret. Removing a secret that does not exist is not an error
+    fn delete(&self, key: &str) -> Result<()>;
+
+    /// Provide a secret as a file. This is needed for certificates, as the MQTT
+    /// library can only read them from files. The file must only be readable by
+    /// the SDK, and should be removed by `delete`
+    fn file_path(&self, key: &str) -> Result<PathBuf>;
+
+    /// List the keys of all stored secrets. This is used to remove certificates
+    /// of things which are no longer paired. Stores which can't list their
+    /// secrets may keep the default, which lists nothing
+    fn keys(&self) -> Result<Vec<String>> {
+        Ok(vec![])
+    }
+}
+
+/// Keys of the CA certificate, certificate and private key of a thing
+pub fn certificate_keys(thing_id: &Uuid) -> [String; 3] {
+    [
+        format!("{}.ca.crt", thing_id),
+        format!("{}.crt", thing_id),
+        format!("{}.key", thing_id),
+    ]
+}
+
+/// The Geeny Thing ID of the thing a key holds a certificate for, if any
+pub fn certificate_owner(key: &str) -> Option<Uuid> {
+    [".ca.crt", ".crt", ".key"]
+        .iter()
+        .filter(|suffix| key.ends_with(*suffix))
+        .filter_map(|suffix| Uuid::parse_str(&key[..key.len() - suffix.len()]).ok())
+        .next()
+}
+
+/// Create the secret store selected in the configuration
+pub fn from_config(cfg: &HubSDKConfig) -> Result<Arc<SecretStore>> {
+    let store: Arc<SecretStore> = match cfg.secret_store {
+        SecretStoreConfig::File => Arc::new(FileSecretStore::new(cfg.mqtt_cert_path.clone())),
+        SecretStoreConfig::Encrypted {
+            ref machine_secret_file,
+            ref runtime_dir,
+        } => Arc::new(EncryptedFileSecretStore::new(
+            cfg.mqtt_cert_path.clone(),
+            machine_secret_file,
+            runtime_dir.clone(),
+        )?),
+    };
+
+    Ok(store)
+}
+
+/// Make sure a key can be used as a file name. Keys can't start with a dot, so
+/// they never clash with temporary files, see `storage::temp_file_for`
+fn check_key(key: &str) -> Result<()> {
+    let valid = !key.is_empty() && !key.starts_with('.') &&
+        key.bytes().all(|b| match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' => true,
+            _ => false,
+        });
+
+    if !valid {
+        bail!("Invalid secret key {:?}", key);
+    }
+
+    Ok(())
+}
+
+fn remove_file(path: &Path) -> Result<()> {
+    // Also remove any leftovers of an interrupted write
+    for file in &[storage::temp_file_for(path), path.to_path_buf()] {
+        match fs::remove_file(file) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(storage::io_error(e, file)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Names of files in a folder which may be keys. Temporary files are skipped
+fn list_files(dir: &Path) -> Result<Vec<String>> {
+    let mut names = vec![];
+    for entry in fs::read_dir(dir).map_err(|e| storage::io_error(e, dir))? {
+        let entry = entry.map_err(|e| storage::io_error(e, dir))?;
+        let is_file = entry.file_type().map(|t| t.is_file()).unwrap_or(false);
+
+        if let Ok(name) = entry.file_name().into_string() {
+            if is_file && check_key(&name).is_ok() {
+                names.push(name);
+            }
+        }
+    }
+
+    Ok(names)
+}
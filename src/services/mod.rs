@@ -10,4 +10,7 @@
 #[cfg(feature = "rest-service")]
 pub mod rest_ipc;
 
-pub use things_db::PartialThingMessage;
+pub use auth_manager::AuthEvent;
+pub use storage::StorageRecovery;
+pub use things_db::{CreateThingResult, CreateThingStatus, DeliveryOptions, PartialThingMessage,
+                    QoS, ThingFailure, ThingInfo, ThingMessage, ThingState};
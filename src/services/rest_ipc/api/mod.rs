@@ -9,3 +9,5 @@
 
 pub mod things;
 pub mod auth;
+pub mod stream;
+pub mod storage;
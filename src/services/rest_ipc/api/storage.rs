@@ -0,0 +1,17 @@
+// Copyright 2017 Telefónica Germany Next GmbH. See the COPYRIGHT file at
+// the top-level directory of this distribution
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use rocket::State;
+use rocket_contrib::Json;
+
+use interface::HubSDK;
+use storage::StorageRecovery;
+
+#[get("/storage/recoveries", format = "application/json")]
+pub fn get_recoveries(sdk: State<HubSDK>) -> Json<Vec<StorageRecovery>> {
+    Json(sdk.storage_recoveries())
+}
@@ -0,0 +1,170 @@
+// Copyright 2017 Telefónica Germany Next GmbH. See the COPYRIGHT file at
+// the top-level directory of this distribution
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Server-Sent Event streams, pushing events to clients as they occur
+//!
+//! Each open stream occupies one of the IPC workers for as long as the client
+//! stays connected, see `RocketConfig::workers`. At most half of the workers
+//! serve streams and waits, see `Slots`
+
+use std::io::{self, Cursor, Read};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+
+use rocket::State;
+use rocket::http::ContentType;
+use rocket::response::Stream;
+use rocket::response::content::Content;
+use serde_json;
+
+use auth_manager::AuthEvent;
+use broadcast::Subscription;
+use errors as echain;
+use things_db::{ThingInfo, ThingMessage};
+
+use interface::HubSDK;
+use services::rest_ipc::slots::{Slot, Slots};
+use super::things::IpcMessage;
+
+type EventResponse<T> = Content<Stream<EventStream<T>>>;
+
+// Interval for sending comments to idle streams, so that dead clients are noticed
+const KEEPALIVE_SECS: u64 = 15;
+
+#[derive(Debug, Serialize)]
+pub struct StreamedMessage {
+    pub serial_number: String,
+    pub msg: IpcMessage,
+}
+
+/// Adapter rendering events from a `Subscription` in the `text/event-stream` format
+pub struct EventStream<T> {
+    subscription: Subscription<T>,
+    event: &'static str,
+    render: fn(T) -> String,
+    buffer: Cursor<Vec<u8>>,
+    flush: bool,
+
+    // Returned once the client disconnects, and the stream is dropped
+    _slot: Slot,
+}
+
+impl<T> EventStream<T> {
+    pub fn new(
+        subscription: Subscription<T>,
+        event: &'static str,
+        render: fn(T) -> String,
+        slot: Slot,
+    ) -> Self {
+        EventStream {
+            subscription: subscription,
+            event: event,
+            render: render,
+            buffer: Cursor::new(vec![]),
+            flush: false,
+            _slot: slot,
+        }
+    }
+
+    pub fn respond(self) -> EventResponse<T> {
+        Content(ContentType::new("text", "event-stream"), Stream::from(self))
+    }
+
+    fn drained(&self) -> bool {
+        self.buffer.position() as usize >= self.buffer.get_ref().len()
+    }
+}
+
+impl<T> Read for EventStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Rocket keeps reading until its chunk buffer is full. A short read after
+        // each complete event makes sure the event is sent to the client right away
+        if self.flush {
+            self.flush = false;
+            return Ok(0);
+        }
+
+        if self.drained() {
+            let frame = match self.subscription
+                .recv_timeout(Duration::from_secs(KEEPALIVE_SECS)) {
+                Ok(event) => format!("event: {}\ndata: {}\n\n", self.event, (self.render)(event)),
+                Err(RecvTimeoutError::Timeout) => ":keepalive\n\n".into(),
+
+                // The SDK has stopped, end the stream
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            };
+
+            self.buffer = Cursor::new(frame.into_bytes());
+        }
+
+        let n = self.buffer.read(buf)?;
+        self.flush = self.drained() && n < buf.len();
+        Ok(n)
+    }
+}
+
+fn render_message(message: ThingMessage) -> String {
+    let streamed = StreamedMessage {
+        serial_number: message.serial_number,
+        msg: IpcMessage::from(message.msg),
+    };
+
+    serde_json::to_string(&streamed).unwrap_or_else(|_| "null".into())
+}
+
+fn render_thing_state(info: ThingInfo) -> String {
+    serde_json::to_string(&info).unwrap_or_else(|_| "null".into())
+}
+
+fn render_auth_event(event: AuthEvent) -> String {
+    serde_json::to_string(&event).unwrap_or_else(|_| "null".into())
+}
+
+#[get("/stream/messages")]
+pub fn stream_all_messages(
+    sdk: State<HubSDK>,
+    slots: State<Slots>,
+) -> Result<EventResponse<ThingMessage>, echain::Error> {
+    let slot = slots.acquire()?;
+    let subscription = sdk.subscribe_all_messages();
+
+    Ok(EventStream::new(subscription, "message", render_message, slot).respond())
+}
+
+#[get("/stream/messages/<serial>")]
+pub fn stream_messages(
+    serial: String,
+    sdk: State<HubSDK>,
+    slots: State<Slots>,
+) -> Result<EventResponse<ThingMessage>, echain::Error> {
+    let slot = slots.acquire()?;
+    let subscription = sdk.subscribe_messages(&serial)?;
+
+    Ok(EventStream::new(subscription, "message", render_message, slot).respond())
+}
+
+#[get("/stream/things")]
+pub fn stream_thing_states(
+    sdk: State<HubSDK>,
+    slots: State<Slots>,
+) -> Result<EventResponse<ThingInfo>, echain::Error> {
+    let slot = slots.acquire()?;
+    let subscription = sdk.subscribe_thing_states();
+
+    Ok(EventStream::new(subscription, "thing", render_thing_state, slot).respond())
+}
+
+#[get("/stream/auth")]
+pub fn stream_auth_events(
+    sdk: State<HubSDK>,
+    slots: State<Slots>,
+) -> Result<EventResponse<AuthEvent>, echain::Error> {
+    let slot = slots.acquire()?;
+    let subscription = sdk.subscribe_auth_events();
+
+    Ok(EventStream::new(subscription, "auth", render_auth_event, slot).respond())
+}
@@ -5,22 +5,137 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use rocket::State;
+use std::io::Read;
+use std::time::Duration;
+
+use base64;
+use rocket::{Data, State};
 use rocket_contrib::{Json, Value};
 
 use geeny_api::models::ThingRequest;
+use uuid::Uuid;
 
 use errors as echain;
-use things_db::PartialThingMessage;
+use errors::ResultExt;
+use things_db::{CreateThingResult, PartialThingMessage, QoS, ThingInfo};
 
 use interface::HubSDK;
+use services::rest_ipc::slots::Slots;
 
 // Convenience type
 type IpcApiResult<T> = Result<Json<T>, echain::Error>;
 
+// Largest accepted body for a raw (octet-stream) message
+const RAW_MESSAGE_LIMIT: u64 = 256 * 1024;
+
+// Longest wait for a thing to become active, a request holds a worker while waiting.
+// Clients wait again if the thing is still not active
+const MAX_WAIT_SECS: u64 = 30;
+
+/// Encoding used for the `msg` field of an `IpcMessage`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadEncoding {
+    /// The payload is used as-is, as UTF-8 text
+    Utf8,
+
+    /// The payload is binary data, encoded as standard Base64
+    Base64,
+}
+
+impl Default for PayloadEncoding {
+    fn default() -> Self {
+        PayloadEncoding::Utf8
+    }
+}
+
+/// JSON representation of a `PartialThingMessage`. Messages without an
+/// `encoding` field are treated as UTF-8 text
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IpcMessage {
+    pub topic: String,
+    pub msg: String,
+
+    #[serde(default)]
+    pub encoding: PayloadEncoding,
+
+    #[serde(default)]
+    pub qos: Option<QoS>,
+
+    #[serde(default)]
+    pub retain: Option<bool>,
+}
+
+impl IpcMessage {
+    fn into_partial(self) -> Result<PartialThingMessage, echain::Error> {
+        let payload = match self.encoding {
+            PayloadEncoding::Utf8 => self.msg.into_bytes(),
+            PayloadEncoding::Base64 => base64::decode(&self.msg)
+                .chain_err(|| format!("Invalid base64 payload for topic {}", self.topic))?,
+        };
+
+        Ok(PartialThingMessage {
+            topic: self.topic,
+            msg: payload,
+            qos: self.qos,
+            retain: self.retain,
+        })
+    }
+}
+
+impl From<PartialThingMessage> for IpcMessage {
+    /// Payloads that are valid UTF-8 are passed as text, all others
+    /// are Base64 encoded
+    fn from(msg: PartialThingMessage) -> Self {
+        let (payload, encoding) = match String::from_utf8(msg.msg) {
+            Ok(text) => (text, PayloadEncoding::Utf8),
+            Err(e) => (base64::encode(&e.into_bytes()), PayloadEncoding::Base64),
+        };
+
+        IpcMessage {
+            topic: msg.topic,
+            msg: payload,
+            encoding: encoding,
+            qos: msg.qos,
+            retain: msg.retain,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct IncomingMessages {
-    pub msgs: Vec<PartialThingMessage>,
+    pub msgs: Vec<IpcMessage>,
+}
+
+fn parse_thing_id(id: &str) -> Result<Uuid, echain::Error> {
+    Uuid::parse_str(id).map_err(|e| echain::Error::from(format!("Invalid thing id {}: {}", id, e)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThingList {
+    pub things: Vec<ThingInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThingRequestList {
+    pub things: Vec<ThingRequest>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateThingResults {
+    pub results: Vec<CreateThingResult>,
+}
+
+#[derive(Debug, FromForm)]
+pub struct WaitParams {
+    pub timeout: u64,
+}
+
+#[derive(Debug, FromForm)]
+pub struct RawMessageParams {
+    pub topic: String,
+    pub qos: Option<u8>,
+    pub retain: Option<bool>,
 }
 
 #[post("/things", format = "application/json", data = "<payload>")]
@@ -30,6 +145,59 @@ pub fn post_thing(payload: Json<ThingRequest>, sdk: State<HubSDK>) -> IpcApiResu
     Ok(Json(json!({"status": "success"})))
 }
 
+#[post("/things/batch", format = "application/json", data = "<payload>")]
+pub fn post_things(
+    payload: Json<ThingRequestList>,
+    sdk: State<HubSDK>,
+) -> IpcApiResult<CreateThingResults> {
+    let results = sdk.create_things(payload.into_inner().things)?;
+
+    Ok(Json(CreateThingResults { results: results }))
+}
+
+#[get("/things", format = "application/json")]
+pub fn get_things(sdk: State<HubSDK>) -> IpcApiResult<ThingList> {
+    let things = sdk.list_things()?;
+
+    Ok(Json(ThingList { things: things }))
+}
+
+#[get("/things/<serial>", format = "application/json")]
+pub fn get_thing(serial: String, sdk: State<HubSDK>) -> IpcApiResult<ThingInfo> {
+    Ok(Json(sdk.get_thing(&serial)?))
+}
+
+#[get("/things/wait/<serial>?<params>", format = "application/json")]
+pub fn wait_for_thing(
+    serial: String,
+    params: WaitParams,
+    sdk: State<HubSDK>,
+    slots: State<Slots>,
+) -> IpcApiResult<ThingInfo> {
+    let _slot = slots.acquire()?;
+    let timeout = Duration::from_secs(params.timeout.min(MAX_WAIT_SECS));
+
+    // Once the timeout has passed, the client learns the current state
+    let info = match sdk.wait_for_active(&serial, timeout) {
+        Err(ref e) if timed_out(e) => sdk.get_thing(&serial)?,
+        result => result?,
+    };
+
+    Ok(Json(info))
+}
+
+fn timed_out(error: &echain::Error) -> bool {
+    match *error.kind() {
+        echain::ErrorKind::WaitTimedOut(..) => true,
+        _ => false,
+    }
+}
+
+#[get("/things/id/<id>", format = "application/json")]
+pub fn get_thing_by_id(id: String, sdk: State<HubSDK>) -> IpcApiResult<ThingInfo> {
+    Ok(Json(sdk.get_thing_by_id(&parse_thing_id(&id)?)?))
+}
+
 #[delete("/things/<serial>", format = "application/json")]
 pub fn delete_thing(serial: String, sdk: State<HubSDK>) -> IpcApiResult<Value> {
     sdk.delete_thing_by_serial(&serial)?;
@@ -48,6 +216,34 @@ pub fn unpair_thing(serial: String, sdk: State<HubSDK>) -> IpcApiResult<Value> {
     })))
 }
 
+#[delete("/things/unpair/id/<id>", format = "application/json")]
+pub fn unpair_thing_by_id(id: String, sdk: State<HubSDK>) -> IpcApiResult<Value> {
+    sdk.unpair_thing_by_id(&parse_thing_id(&id)?)?;
+
+    Ok(Json(json!({
+        "status": "success",
+    })))
+}
+
+#[post("/things/rotate/<serial>", format = "application/json")]
+pub fn rotate_certificates(serial: String, sdk: State<HubSDK>) -> IpcApiResult<Value> {
+    sdk.rotate_certificates(&serial)?;
+
+    Ok(Json(json!({
+        "status": "success",
+    })))
+}
+
+fn decode_messages(
+    payload: Json<IncomingMessages>,
+) -> Result<Vec<PartialThingMessage>, echain::Error> {
+    payload
+        .into_inner()
+        .msgs
+        .into_iter()
+        .map(|m| m.into_partial())
+        .collect()
+}
 
 #[post("/messages/<serial>", format = "application/json", data = "<payload>")]
 pub fn post_message(
@@ -55,7 +251,64 @@ pub fn post_message(
     payload: Json<IncomingMessages>,
     sdk: State<HubSDK>,
 ) -> IpcApiResult<Value> {
-    sdk.send_messages(&serial, &payload.msgs)?;
+    let msgs = decode_messages(payload)?;
+
+    sdk.send_messages(&serial, &msgs)?;
+
+    Ok(Json(json!({
+        "status": "success",
+    })))
+}
+
+#[post("/messages/id/<id>", format = "application/json", data = "<payload>")]
+pub fn post_message_by_id(
+    id: String,
+    payload: Json<IncomingMessages>,
+    sdk: State<HubSDK>,
+) -> IpcApiResult<Value> {
+    let msgs = decode_messages(payload)?;
+
+    sdk.send_messages_by_id(&parse_thing_id(&id)?, &msgs)?;
+
+    Ok(Json(json!({
+        "status": "success",
+    })))
+}
+
+#[post("/messages/raw/<serial>?<params>", format = "application/octet-stream",
+       data = "<payload>")]
+pub fn post_raw_message(
+    serial: String,
+    params: RawMessageParams,
+    payload: Data,
+    sdk: State<HubSDK>,
+) -> IpcApiResult<Value> {
+    // Read one byte more than accepted, to tell whether the body was too large
+    let mut body = vec![];
+    payload
+        .open()
+        .take(RAW_MESSAGE_LIMIT + 1)
+        .read_to_end(&mut body)
+        .chain_err(|| "Failed to read message body")?;
+
+    if body.len() as u64 > RAW_MESSAGE_LIMIT {
+        return Err(echain::ErrorKind::PayloadTooLarge(RAW_MESSAGE_LIMIT).into());
+    }
+
+    let qos = match params.qos {
+        Some(level) => Some(QoS::from_level(level)
+            .ok_or_else(|| echain::Error::from(format!("Invalid QoS level {}", level)))?),
+        None => None,
+    };
+
+    let msg = PartialThingMessage {
+        topic: params.topic,
+        msg: body,
+        qos: qos,
+        retain: params.retain,
+    };
+
+    sdk.send_messages(&serial, &[msg])?;
 
     Ok(Json(json!({
         "status": "success",
@@ -66,5 +319,16 @@ pub fn post_message(
 pub fn get_message(serial: String, sdk: State<HubSDK>) -> IpcApiResult<IncomingMessages> {
     let msgs = sdk.receive_messages(&serial)?;
 
-    Ok(Json(IncomingMessages { msgs: msgs }))
+    Ok(Json(IncomingMessages {
+        msgs: msgs.into_iter().map(IpcMessage::from).collect(),
+    }))
+}
+
+#[get("/messages/id/<id>", format = "application/json")]
+pub fn get_message_by_id(id: String, sdk: State<HubSDK>) -> IpcApiResult<IncomingMessages> {
+    let msgs = sdk.receive_messages_by_id(&parse_thing_id(&id)?)?;
+
+    Ok(Json(IncomingMessages {
+        msgs: msgs.into_iter().map(IpcMessage::from).collect(),
+    }))
 }
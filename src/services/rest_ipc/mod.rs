@@ -16,12 +16,15 @@ use log;
 
 pub mod api;
 pub mod rocket_config;
+pub mod slots;
 
 use interface::{self, HubSDKConfig};
 use self::rocket_config::RocketConfig;
+use self::slots::Slots;
 
 pub fn prep_rocket(config: RocketConfig, sdk: interface::HubSDK) -> rocket::Rocket {
     let rocket_cfg = config.render();
+    let slots = Slots::for_workers(config.workers);
 
     log::debug!("Starting Rocket; config: {:?}", rocket_cfg);
 
@@ -31,18 +34,38 @@ pub fn prep_rocket(config: RocketConfig, sdk: interface::HubSDK) -> rocket::Rock
             routes![
                 // Things API
                 api::things::post_thing,
+                api::things::post_things,
+                api::things::get_things,
+                api::things::get_thing,
+                api::things::get_thing_by_id,
+                api::things::wait_for_thing,
                 api::things::post_message,
+                api::things::post_raw_message,
                 api::things::get_message,
+                api::things::post_message_by_id,
+                api::things::get_message_by_id,
                 api::things::unpair_thing,
+                api::things::unpair_thing_by_id,
+                api::things::rotate_certificates,
                 api::things::delete_thing,
 
                 // Auth API
                 api::auth::login,
                 api::auth::logout,
                 api::auth::token_check,
+
+                // Event Streams
+                api::stream::stream_all_messages,
+                api::stream::stream_messages,
+                api::stream::stream_thing_states,
+                api::stream::stream_auth_events,
+
+                // Storage API
+                api::storage::get_recoveries,
             ],
         )
         .manage(sdk)
+        .manage(slots)
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
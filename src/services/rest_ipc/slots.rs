@@ -0,0 +1,84 @@
+// Copyright 2017 Telefónica Germany Next GmbH. See the COPYRIGHT file at
+// the top-level directory of this distribution
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Limit on requests which hold an IPC worker for a long time, i.e. event
+//! streams and waits for things. Without a limit, they could occupy all workers,
+//! and no other request would be served
+
+use std::cmp::max;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use errors::*;
+
+/// Slots for long running requests, shared by all workers
+pub struct Slots {
+    in_use: Arc<AtomicUsize>,
+    limit: usize,
+}
+
+impl Slots {
+    /// Allow long running requests to occupy half of the workers, the remaining
+    /// workers serve all other requests. At least one long running request is
+    /// allowed, even with a single worker
+    pub fn for_workers(workers: u16) -> Self {
+        Slots {
+            in_use: Arc::new(AtomicUsize::new(0)),
+            limit: usize::from(max(1, workers / 2)),
+        }
+    }
+
+    /// Take a slot, which is returned when dropped. Fails once all slots are in use
+    pub fn acquire(&self) -> Result<Slot> {
+        let taken = self.in_use.fetch_add(1, Ordering::SeqCst);
+        let slot = Slot {
+            in_use: self.in_use.clone(),
+        };
+
+        if taken >= self.limit {
+            // Dropping the slot returns it right away
+            bail!(ErrorKind::TooManyLongRequests(self.limit));
+        }
+
+        Ok(slot)
+    }
+}
+
+/// A slot taken by a long running request
+pub struct Slot {
+    in_use: Arc<AtomicUsize>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.in_use.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Slots;
+
+    #[test]
+    fn single_worker_serves_one_long_request() {
+        let slots = Slots::for_workers(1);
+
+        let slot = slots.acquire().expect("No slot for a single worker");
+        assert!(slots.acquire().is_err());
+
+        drop(slot);
+        assert!(slots.acquire().is_ok());
+    }
+
+    #[test]
+    fn two_workers_serve_one_long_request() {
+        let slots = Slots::for_workers(2);
+
+        let _slot = slots.acquire().expect("No slot for two workers");
+        assert!(slots.acquire().is_err());
+    }
+}
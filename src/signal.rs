@@ -0,0 +1,88 @@
+// Copyright 2017 Telefónica Germany Next GmbH. See the COPYRIGHT file at
+// the top-level directory of this distribution
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Signalling between the `HubSDK` handle and its background threads
+
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct SignalState {
+    shutdown: bool,
+
+    // Set by `Signal::wake`, cleared by `Signal::wait`
+    woken: bool,
+}
+
+/// Thread safe handle used to ask background threads to stop, or to wake
+/// a thread waiting for work
+#[derive(Clone, Default)]
+pub struct Signal {
+    inner: Arc<(Mutex<SignalState>, Condvar)>,
+}
+
+impl Signal {
+    fn state(&self) -> MutexGuard<'_, SignalState> {
+        // The state is a plain flag, it can't be left inconsistent by a panic
+        self.inner.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Ask all threads waiting on this signal to stop
+    pub fn shutdown(&self) {
+        self.state().shutdown = true;
+        self.inner.1.notify_all();
+    }
+
+    /// Sleep for up to `timeout`, waking early if a shutdown is requested.
+    /// Returns `true` if a shutdown was requested
+    pub fn sleep(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state();
+
+        while !state.shutdown {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+
+            state = match self.inner.1.wait_timeout(state, deadline - now) {
+                Ok((guard, _)) => guard,
+                Err(e) => e.into_inner().0,
+            };
+        }
+
+        state.shutdown
+    }
+
+    /// Wake the thread waiting in `Signal::wait`, e.g. because new work arrived
+    pub fn wake(&self) {
+        self.state().woken = true;
+        self.inner.1.notify_all();
+    }
+
+    /// Wait for up to `timeout`, waking early if `Signal::wake` is called or a
+    /// shutdown is requested. Returns `true` if a shutdown was requested
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state();
+
+        while !state.shutdown && !state.woken {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+
+            state = match self.inner.1.wait_timeout(state, deadline - now) {
+                Ok((guard, _)) => guard,
+                Err(e) => e.into_inner().0,
+            };
+        }
+
+        state.woken = false;
+        state.shutdown
+    }
+}
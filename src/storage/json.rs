@@ -0,0 +1,62 @@
+// Copyright 2017 Telefónica Germany Next GmbH. See the COPYRIGHT file at
+// the top-level directory of this distribution
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fs::{self, Permissions};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use errors::*;
+use super::{io_error, read_file, with_suffix, write_file, Backend};
+
+/// A document stored as a JSON file. The file is replaced as a whole on every change
+pub struct JsonFile {
+    path: PathBuf,
+}
+
+impl JsonFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        // Make sure permissions are correct, these files contain sensitive information
+        if path.exists() {
+            fs::set_permissions(path, Permissions::from_mode(0o600))
+                .map_err(|e| io_error(e, path))?;
+        }
+
+        Ok(JsonFile { path: path.to_path_buf() })
+    }
+}
+
+impl Backend for JsonFile {
+    fn load(&self) -> Result<Option<Vec<u8>>> {
+        read_file(&self.path)
+    }
+
+    fn save(&self, contents: &[u8]) -> Result<()> {
+        write_file(&self.path, contents)
+    }
+
+    fn keep_copy(&self, suffix: &str, contents: &[u8]) -> Result<String> {
+        write_file(&with_suffix(&self.path, suffix), contents)?;
+        Ok(self.describe_copy(suffix))
+    }
+
+    fn load_copy(&self, suffix: &str) -> Result<Option<Vec<u8>>> {
+        read_file(&with_suffix(&self.path, suffix))
+    }
+
+    fn describe_copy(&self, suffix: &str) -> String {
+        format!("{:?}", with_suffix(&self.path, suffix))
+    }
+
+    fn remove(&self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(io_error(e, &self.path)),
+        }
+    }
+}
@@ -0,0 +1,652 @@
+// Copyright 2017 Telefónica Germany Next GmbH. See the COPYRIGHT file at
+// the top-level directory of this distribution
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Helpers for loading and creating the files used to persist SDK state
+
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+
+use errors::*;
+use interface::StorageConfig;
+
+// A JSON file per document, as used by previous versions of the SDK
+mod json;
+
+// All documents in a single SQLite database
+#[cfg(feature = "sqlite-storage")]
+mod sqlite;
+
+/// Where the contents of a `Store` are persisted
+trait Backend: Send + Sync {
+    /// The serialized contents, if any have been saved
+    fn load(&self) -> Result<Option<Vec<u8>>>;
+
+    /// Replace the serialized contents. Either all or none of the contents are
+    /// saved, even if the hub loses power while saving
+    fn save(&self, contents: &[u8]) -> Result<()>;
+
+    /// Keep a copy of serialized contents next to the document, under the given
+    /// suffix. Returns a description of where the copy is kept
+    fn keep_copy(&self, suffix: &str, contents: &[u8]) -> Result<String>;
+
+    /// A copy kept under the given suffix, if any
+    fn load_copy(&self, suffix: &str) -> Result<Option<Vec<u8>>>;
+
+    /// A description of where a copy with the given suffix is kept
+    fn describe_copy(&self, suffix: &str) -> String;
+
+    /// Remove the document. Copies are kept
+    fn remove(&self) -> Result<()>;
+}
+
+/// Number of rotated backups kept of a document, unless `Document::backups`
+/// says otherwise. A backup is made of every document that could be read when
+/// it is opened
+pub const BACKUPS: usize = 3;
+
+fn backup_suffix(n: usize) -> String {
+    format!("bak.{}", n)
+}
+
+/// A document that could not be read when the SDK started, and how it was recovered
+///
+/// Please see `HubSDK::storage_recoveries` for further examples of usage
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StorageRecovery {
+    /// The affected document, `elements` or `credentials`. Message outboxes are
+    /// recovered when they are first used, and are not reported
+    pub document: String,
+
+    /// Why the document could not be read
+    pub reason: String,
+
+    /// Where the unreadable document was moved to
+    pub quarantined_to: String,
+
+    /// The backup the document was restored from. `None` if no backup could be
+    /// read, in which case the document was reset, e.g. forgetting all paired things.
+    /// Please see `HubSDKConfig::reset_corrupt_storage`
+    pub restored_from: Option<String>,
+}
+
+// The outcome of reading a saved document
+enum Decoded<T> {
+    Document(T),
+
+    // The document could not be parsed
+    Corrupt(Error),
+}
+
+/// Converts a serialized document from one version to the next
+pub type Migration = fn(Value) -> Result<Value>;
+
+/// A type persisted in a `Store`. Documents are saved together with their
+/// version, so that documents written by previous versions of the SDK can be
+/// upgraded when they are opened
+pub trait Document: Serialize + DeserializeOwned + Default {
+    /// The upgrades of the serialized document. The migration at index `n`
+    /// converts version `n` into version `n + 1`, the number of migrations is
+    /// the current version. Version 0 is a document saved without a version
+    fn migrations() -> Vec<Migration>;
+
+    /// The number of rotated backups kept of the document. Documents that are
+    /// only of use while they are current may keep none
+    fn backups() -> usize {
+        BACKUPS
+    }
+}
+
+/// The first migration of every document. Version 1 only adds the version,
+/// the contents are unchanged
+pub fn add_version(document: Value) -> Result<Value> {
+    Ok(document)
+}
+
+// The layout of a saved document
+#[derive(Serialize)]
+struct Envelope<'a, T: 'a> {
+    version: usize,
+    data: &'a T,
+}
+
+/// A document persisted by the SDK, such as the paired things or the current
+/// user. Changes made with `Store::access_mut` are saved before it returns.
+/// Clones refer to the same document
+pub struct Store<T> {
+    data: Arc<Mutex<T>>,
+    backend: Arc<Backend>,
+    version: usize,
+    recovery: Option<StorageRecovery>,
+}
+
+impl<T> Clone for Store<T> {
+    fn clone(&self) -> Self {
+        Store {
+            data: self.data.clone(),
+            backend: self.backend.clone(),
+            version: self.version,
+            recovery: self.recovery.clone(),
+        }
+    }
+}
+
+impl<T: Document> Store<T> {
+    /// Open a document, or create it with default contents if it does not exist.
+    /// With `StorageConfig::Json` the document is kept in `file`. Otherwise `file`
+    /// is only read once, to import a document written by a previous version.
+    /// A document that can not be parsed is quarantined, and restored from the most
+    /// recent backup that can be read. Without one, the document is reset if `reset`
+    /// is set, otherwise opening fails with the `corrupt` error kind. A document that
+    /// can't be upgraded fails with `StorageCorrupt`
+    pub fn open<F>(
+        config: &StorageConfig,
+        name: &str,
+        file: &Path,
+        reset: bool,
+        corrupt: F,
+    ) -> Result<Self>
+    where
+        F: Fn() -> ErrorKind,
+    {
+        let backend = open_backend(config, name, file)?;
+        if let StorageConfig::Sqlite { .. } = *config {
+            if backend.load()?.is_none() {
+                import(&*backend, file)?;
+            }
+        }
+
+        let mut store = Store {
+            data: Arc::new(Mutex::new(T::default())),
+            backend: backend,
+            version: T::migrations().len(),
+            recovery: None,
+        };
+
+        let contents = match store.backend.load()? {
+            Some(contents) => contents,
+            None => {
+                store.access_mut(|_| ())?;
+                return Ok(store);
+            }
+        };
+
+        match store.decode(name, file, &contents, &corrupt)? {
+            Decoded::Document(data) => {
+                *store.lock() = data;
+
+                // Backups are not needed to operate
+                if let Err(e) = store.rotate_backups(&contents) {
+                    log::warn!("Failed to keep a backup of the {}: {}", name, e);
+                }
+            }
+            Decoded::Corrupt(e) => {
+                store.recovery = Some(store.recover(name, file, &contents, e, reset, &corrupt)?);
+            }
+        }
+
+        // Save upgraded or restored documents
+        store.access_mut(|_| ())?;
+        Ok(store)
+    }
+
+    /// How the document was recovered, if it could not be read when it was opened
+    pub fn recovery(&self) -> Option<StorageRecovery> {
+        self.recovery.clone()
+    }
+
+    /// Read the document
+    pub fn access<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        Ok(f(&self.lock()))
+    }
+
+    /// Change the document. All changes made by `f` are saved together
+    pub fn access_mut<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        let mut data = self.lock();
+        let result = f(&mut data);
+
+        let envelope = Envelope {
+            version: self.version,
+            data: &*data,
+        };
+        let contents =
+            serde_json::to_vec_pretty(&envelope).chain_err(|| "Failed to serialize document")?;
+
+        self.backend.save(&contents)?;
+        Ok(result)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, T> {
+        // A panic while changing the document leaves at most part of the change
+        // unsaved, it is saved with the next change
+        self.data.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Parse a saved document, upgrading it if it was saved by a previous version
+    fn decode<F>(
+        &self,
+        name: &str,
+        file: &Path,
+        contents: &[u8],
+        corrupt: &F,
+    ) -> Result<Decoded<T>>
+    where
+        F: Fn() -> ErrorKind,
+    {
+        let (version, document) = match serde_json::from_slice::<Value>(contents) {
+            Ok(document) => split_envelope(document),
+            Err(e) => return Ok(Decoded::Corrupt(Error::with_chain(e, corrupt()))),
+        };
+
+        if version > self.version {
+            bail!(
+                "The {} were saved by a newer version of the SDK (version {}, expected {})",
+                name,
+                version,
+                self.version
+            );
+        }
+
+        if version == self.version {
+            return Ok(match serde_json::from_value(document) {
+                Ok(data) => Decoded::Document(data),
+                Err(e) => Decoded::Corrupt(Error::with_chain(e, corrupt())),
+            });
+        }
+
+        // Keep the original, in case the upgrade fails or loses information
+        let backup = self.backend
+            .keep_copy(&format!("v{}.bak", version), contents)?;
+
+        let upgraded = T::migrations()[version..]
+            .iter()
+            .try_fold(document, |document, migrate| migrate(document))
+            .and_then(|document| {
+                serde_json::from_value(document).chain_err(|| "Unexpected contents")
+            })
+            .chain_err(|| {
+                format!(
+                    "Failed to upgrade the {} from version {}, the original is kept in {}",
+                    name,
+                    version,
+                    backup
+                )
+            })
+            .map_err(|e| Error::with_chain(e, ErrorKind::StorageCorrupt(file.into())))?;
+
+        log::info!(
+            "Upgraded the {} from version {} to {}, the original is kept in {}",
+            name,
+            version,
+            self.version,
+            backup
+        );
+        Ok(Decoded::Document(upgraded))
+    }
+
+    /// Keep a copy of a document that could be read. Older copies are kept as
+    /// well, up to `BACKUPS` copies
+    fn rotate_backups(&self, contents: &[u8]) -> Result<()> {
+        let backups = T::backups();
+        if backups == 0 {
+            return Ok(());
+        }
+
+        // Keep older copies, rather than several copies of the same document
+        if self.backend.load_copy(&backup_suffix(1))?.as_ref().map(|c| &c[..]) == Some(contents) {
+            return Ok(());
+        }
+
+        for n in (1..backups).rev() {
+            if let Some(older) = self.backend.load_copy(&backup_suffix(n))? {
+                self.backend.keep_copy(&backup_suffix(n + 1), &older)?;
+            }
+        }
+
+        self.backend.keep_copy(&backup_suffix(1), contents)?;
+        Ok(())
+    }
+
+    /// Quarantine a document that can't be read, and restore the most recent
+    /// backup that can be read. If there is none, the document is reset if `reset`
+    /// is set, otherwise `error` is returned
+    fn recover<F>(
+        &self,
+        name: &str,
+        file: &Path,
+        contents: &[u8],
+        error: Error,
+        reset: bool,
+        corrupt: &F,
+    ) -> Result<StorageRecovery>
+    where
+        F: Fn() -> ErrorKind,
+    {
+        let reason = error
+            .iter()
+            .map(|cause| cause.to_string())
+            .collect::<Vec<_>>()
+            .join(": ");
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let quarantined_to = self.backend
+            .keep_copy(&format!("corrupt.{}", now), contents)?;
+
+        log::error!(
+            "The {} can't be read ({}), moved them to {}",
+            name,
+            reason,
+            quarantined_to
+        );
+
+        let mut restored_from = None;
+        for n in 1..T::backups() + 1 {
+            let suffix = backup_suffix(n);
+            let backup = match self.backend.load_copy(&suffix)? {
+                Some(backup) => backup,
+                None => continue,
+            };
+
+            match self.decode(name, file, &backup, corrupt) {
+                Ok(Decoded::Document(data)) => {
+                    *self.lock() = data;
+                    restored_from = Some(self.backend.describe_copy(&suffix));
+                    break;
+                }
+                Ok(Decoded::Corrupt(e)) => log::error!("Backup of the {} is corrupt: {}", name, e),
+                Err(e) => log::error!("Failed to restore a backup of the {}: {}", name, e),
+            }
+        }
+
+        match restored_from {
+            Some(ref backup) => log::warn!("Restored the {} from {}", name, backup),
+            None if reset => log::error!("No usable backup of the {}, starting over", name),
+            None => {
+                log::error!("No usable backup of the {}, not starting over", name);
+                return Err(error);
+            }
+        }
+
+        Ok(StorageRecovery {
+            document: name.into(),
+            reason: reason,
+            quarantined_to: quarantined_to,
+            restored_from: restored_from,
+        })
+    }
+}
+
+/// Separate a saved document from its version. Documents saved without a
+/// version are version 0
+fn split_envelope(document: Value) -> (usize, Value) {
+    if let Value::Object(mut fields) = document {
+        let version = fields.get("version").and_then(|v| v.as_u64());
+
+        if let (Some(version), true) = (version, fields.len() == 2) {
+            if let Some(data) = fields.remove("data") {
+                return (version as usize, data);
+            }
+        }
+
+        return (0, Value::Object(fields));
+    }
+
+    (0, document)
+}
+
+/// Remove a document, e.g. one kept for a thing that is no longer paired. Removing
+/// a document that does not exist is not an error
+pub fn remove(config: &StorageConfig, name: &str, file: &Path) -> Result<()> {
+    open_backend(config, name, file)?.remove()
+}
+
+fn open_backend(config: &StorageConfig, name: &str, file: &Path) -> Result<Arc<Backend>> {
+    let backend: Arc<Backend> = match *config {
+        StorageConfig::Json => Arc::new(json::JsonFile::open(file)?),
+        StorageConfig::Sqlite { ref database_file } => open_database(database_file, name)?,
+    };
+
+    Ok(backend)
+}
+
+#[cfg(feature = "sqlite-storage")]
+fn open_database(path: &Path, name: &str) -> Result<Arc<Backend>> {
+    Ok(Arc::new(sqlite::SqliteDocument::open(path, name)?))
+}
+
+#[cfg(not(feature = "sqlite-storage"))]
+fn open_database(path: &Path, _name: &str) -> Result<Arc<Backend>> {
+    bail!(
+        "Can't open {:?}, the SDK was built without the sqlite-storage feature",
+        path
+    );
+}
+
+/// Copy a document written by a previous version of the SDK into `backend`, where
+/// it is upgraded, or recovered if corrupt, once opened. The file is renamed
+/// afterwards, so it is not mistaken for the current document
+fn import(backend: &Backend, file: &Path) -> Result<()> {
+    let contents = match read_file(file)? {
+        Some(contents) => contents,
+        None => return Ok(()),
+    };
+
+    backend.save(&contents)?;
+
+    let imported = with_suffix(file, "imported");
+    fs::rename(file, &imported).map_err(|e| io_error(e, file))?;
+
+    log::info!("Imported {:?}, the original was moved to {:?}", file, imported);
+    Ok(())
+}
+
+/// Read a file, returning `None` if it does not exist
+pub fn read_file(path: &Path) -> Result<Option<Vec<u8>>> {
+    let mut contents = vec![];
+    match File::open(path) {
+        Ok(mut file) => {
+            file.read_to_end(&mut contents)
+                .map_err(|e| io_error(e, path))?;
+            Ok(Some(contents))
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(io_error(e, path)),
+    }
+}
+
+/// Replace the contents of a file, so that it is never seen half written. Nothing
+/// is written if the file already has the same contents, to spare flash storage.
+/// The file is only readable by its owner
+pub fn write_file(path: &Path, contents: &[u8]) -> Result<()> {
+    if read_file(path)?.as_ref().map(|c| &c[..]) == Some(contents) {
+        return Ok(());
+    }
+
+    let temp = temp_file_for(path);
+
+    // Scope to ensure file closed and written
+    {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temp)
+            .map_err(|e| io_error(e, &temp))?;
+        file.write_all(contents).map_err(|e| io_error(e, &temp))?;
+        file.sync_all().map_err(|e| io_error(e, &temp))?;
+    }
+
+    fs::rename(&temp, path).map_err(|e| io_error(e, path))?;
+
+    // Make sure the rename itself survives a power loss
+    if let Some(dir) = path.parent() {
+        if let Err(e) = File::open(dir).and_then(|d| d.sync_all()) {
+            log::warn!("Failed to sync {:?}: {}", dir, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// A file next to `path`, named like it with an additional suffix
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let name = path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    path.with_file_name(format!("{}.{}", name, suffix))
+}
+
+/// The file used by `write_file` while a file is being replaced. Its name starts
+/// with a dot, next to the replaced file
+pub fn temp_file_for(path: &Path) -> PathBuf {
+    let name = path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    path.with_file_name(format!(".{}.tmp", name))
+}
+
+/// Create a folder (and its parents) if necessary, and set its permissions
+pub fn create_dir(path: &Path, mode: u32) -> Result<()> {
+    fs::create_dir_all(path).map_err(|e| io_error(e, path))?;
+    fs::set_permissions(path, Permissions::from_mode(mode))
+        .map_err(|e| io_error(e, path))?;
+    Ok(())
+}
+
+/// Convert an IO error for a given path into a matchable error kind
+pub fn io_error(e: io::Error, path: &Path) -> Error {
+    let kind = match e.kind() {
+        io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied(path.to_path_buf()),
+        io::ErrorKind::NotFound => ErrorKind::MissingDirectory(
+            path.parent().unwrap_or(path).to_path_buf(),
+        ),
+        _ => ErrorKind::Msg(format!("IO error on {:?}", path)),
+    };
+
+    Error::with_chain(e, kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use serde_json::Value;
+    use uuid::Uuid;
+
+    use super::{add_version, Document, Migration, Store};
+    use errors::*;
+    use interface::StorageConfig;
+
+    #[derive(Serialize, Deserialize, Default)]
+    struct Counter {
+        count: u32,
+    }
+
+    impl Document for Counter {
+        fn migrations() -> Vec<Migration> {
+            vec![add_version]
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Default)]
+    struct Upgraded {
+        count: u32,
+    }
+
+    fn fail(_: Value) -> Result<Value> {
+        bail!("Unexpected contents")
+    }
+
+    impl Document for Upgraded {
+        fn migrations() -> Vec<Migration> {
+            vec![add_version, fail]
+        }
+    }
+
+    fn document_with(contents: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("hub-sdk-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).expect("Failed to create test folder");
+
+        let file = dir.join("counter.json");
+        fs::write(&file, contents).expect("Failed to write test document");
+        file
+    }
+
+    fn open<T: Document>(file: &Path, reset: bool) -> Result<Store<T>> {
+        let path = file.to_path_buf();
+        Store::open(&StorageConfig::Json, "counter", file, reset, || {
+            ErrorKind::CorruptElementFile(path.clone())
+        })
+    }
+
+    #[test]
+    fn corrupt_document_without_backup_fails_unless_reset() {
+        let file = document_with("{\"version\": 1, \"data\": {\"cou");
+
+        match open::<Counter>(&file, false) {
+            Err(Error(ErrorKind::CorruptElementFile(ref path), _)) => assert_eq!(path, &file),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Opened a corrupt document"),
+        }
+
+        let store = open::<Counter>(&file, true).expect("Failed to reset the document");
+        let recovery = store.recovery().expect("Reset was not reported");
+        assert_eq!(recovery.document, "counter");
+        assert!(recovery.restored_from.is_none());
+        assert_eq!(store.access(|c| c.count).unwrap(), 0);
+
+        let _ = fs::remove_dir_all(file.parent().unwrap());
+    }
+
+    #[test]
+    fn corrupt_document_is_restored_from_backup() {
+        let file = document_with("{\"version\": 1, \"data\": {\"count\": 3}}");
+        open::<Counter>(&file, false).expect("Failed to open the document");
+
+        fs::write(&file, "{\"version\": 1, \"data\": {\"cou").unwrap();
+        let store = open::<Counter>(&file, false).expect("Failed to restore the document");
+
+        assert!(store.recovery().and_then(|r| r.restored_from).is_some());
+        assert_eq!(store.access(|c| c.count).unwrap(), 3);
+
+        let _ = fs::remove_dir_all(file.parent().unwrap());
+    }
+
+    #[test]
+    fn failed_upgrade_is_storage_corrupt() {
+        let file = document_with("{\"version\": 1, \"data\": {\"count\": 3}}");
+
+        match open::<Upgraded>(&file, true) {
+            Err(Error(ErrorKind::StorageCorrupt(ref path), _)) => assert_eq!(path, &file),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Opened a document that can't be upgraded"),
+        }
+
+        let _ = fs::remove_dir_all(file.parent().unwrap());
+    }
+}
@@ -0,0 +1,297 @@
+// Copyright 2017 Telefónica Germany Next GmbH. See the COPYRIGHT file at
+// the top-level directory of this distribution
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::BTreeMap;
+use std::fs::{self, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use rusqlite::{self, Connection};
+use serde_json::{self, Map, Value};
+
+use errors::*;
+use super::{io_error, Backend};
+
+// Changes to the layout of the database, the statements at index `n` upgrade
+// version `n` to `n + 1`. The version is stored as the `user_version` of the database
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE documents (
+        name TEXT PRIMARY KEY NOT NULL,
+        contents BLOB NOT NULL
+    );",
+    "CREATE TABLE records (
+        document TEXT NOT NULL,
+        key TEXT NOT NULL,
+        contents BLOB NOT NULL,
+        PRIMARY KEY (document, key)
+    );",
+];
+
+// Key of the record holding everything but the entries of maps, e.g. the version
+const ROOT_KEY: &str = "";
+
+/// A document stored in a SQLite database. Several documents may share a database.
+///
+/// Each entry of a map in the document, e.g. each paired thing, is stored as a
+/// record of its own. Saving the document only writes the records which changed,
+/// in a single transaction. Copies of the document are stored as a whole
+pub struct SqliteDocument {
+    path: PathBuf,
+    name: String,
+    conn: Mutex<Connection>,
+}
+
+impl SqliteDocument {
+    pub fn open(path: &Path, name: &str) -> Result<Self> {
+        let conn = Connection::open(path).chain_err(|| db_error(path))?;
+
+        // Make sure permissions are correct, the database contains sensitive information
+        fs::set_permissions(path, Permissions::from_mode(0o600))
+            .map_err(|e| io_error(e, path))?;
+
+        // Commits survive a power loss. Other documents may be saved at the same
+        // time, wait for them rather than failing
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA synchronous = FULL;
+             PRAGMA busy_timeout = 5000;",
+        ).chain_err(|| db_error(path))?;
+
+        migrate(&conn, path)?;
+
+        Ok(SqliteDocument {
+            path: path.to_path_buf(),
+            name: name.into(),
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<Connection> {
+        // A panic can't leave the connection inconsistent, open transactions
+        // are rolled back when dropped
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn copy_name(&self, suffix: &str) -> String {
+        format!("{}.{}", self.name, suffix)
+    }
+
+    fn load_as(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let loaded = self.conn().query_row(
+            "SELECT contents FROM documents WHERE name = ?",
+            &[&name],
+            |row| row.get(0),
+        );
+
+        match loaded {
+            Ok(contents) => Ok(Some(contents)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e).chain_err(|| db_error(&self.path)),
+        }
+    }
+
+    fn records(&self, conn: &Connection) -> Result<BTreeMap<String, Vec<u8>>> {
+        let mut stmt = conn.prepare("SELECT key, contents FROM records WHERE document = ?")
+            .chain_err(|| db_error(&self.path))?;
+        let rows = stmt.query_map(&[&self.name], |row| -> (String, Vec<u8>) {
+            (row.get(0), row.get(1))
+        }).chain_err(|| db_error(&self.path))?;
+
+        let mut records = BTreeMap::new();
+        for row in rows {
+            let (key, contents) = row.chain_err(|| db_error(&self.path))?;
+            records.insert(key, contents);
+        }
+
+        Ok(records)
+    }
+
+    fn load_records(&self) -> Result<Option<Vec<u8>>> {
+        let records = self.records(&self.conn())?;
+
+        // Saved by a previous version as a whole, or never saved
+        if records.is_empty() {
+            return self.load_as(&self.name);
+        }
+
+        join_records(records).map(Some)
+    }
+
+    fn save_records(&self, contents: &[u8]) -> Result<()> {
+        let records = split_records(contents);
+
+        let mut conn = self.conn();
+        let tx = conn.transaction().chain_err(|| db_error(&self.path))?;
+
+        let saved = self.records(&tx)?;
+
+        for (key, contents) in &records {
+            if saved.get(key) == Some(contents) {
+                continue;
+            }
+
+            tx.execute(
+                "INSERT OR REPLACE INTO records (document, key, contents) VALUES (?, ?, ?)",
+                &[&self.name, key, contents],
+            ).chain_err(|| db_error(&self.path))?;
+        }
+
+        for key in saved.keys().filter(|key| !records.contains_key(*key)) {
+            tx.execute(
+                "DELETE FROM records WHERE document = ? AND key = ?",
+                &[&self.name, key],
+            ).chain_err(|| db_error(&self.path))?;
+        }
+
+        // Saved as a whole by a previous version
+        tx.execute("DELETE FROM documents WHERE name = ?", &[&self.name])
+            .chain_err(|| db_error(&self.path))?;
+
+        tx.commit().chain_err(|| db_error(&self.path))
+    }
+
+    fn save_as(&self, name: &str, contents: &[u8]) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction().chain_err(|| db_error(&self.path))?;
+
+        tx.execute(
+            "INSERT OR REPLACE INTO documents (name, contents) VALUES (?, ?)",
+            &[&name, &contents.to_vec()],
+        ).chain_err(|| db_error(&self.path))?;
+
+        tx.commit().chain_err(|| db_error(&self.path))
+    }
+}
+
+impl Backend for SqliteDocument {
+    fn load(&self) -> Result<Option<Vec<u8>>> {
+        self.load_records()
+    }
+
+    fn save(&self, contents: &[u8]) -> Result<()> {
+        self.save_records(contents)
+    }
+
+    fn keep_copy(&self, suffix: &str, contents: &[u8]) -> Result<String> {
+        self.save_as(&self.copy_name(suffix), contents)?;
+        Ok(self.describe_copy(suffix))
+    }
+
+    fn load_copy(&self, suffix: &str) -> Result<Option<Vec<u8>>> {
+        self.load_as(&self.copy_name(suffix))
+    }
+
+    fn describe_copy(&self, suffix: &str) -> String {
+        format!("document {:?} of {:?}", self.copy_name(suffix), self.path)
+    }
+
+    fn remove(&self) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction().chain_err(|| db_error(&self.path))?;
+
+        tx.execute("DELETE FROM records WHERE document = ?", &[&self.name])
+            .chain_err(|| db_error(&self.path))?;
+        tx.execute("DELETE FROM documents WHERE name = ?", &[&self.name])
+            .chain_err(|| db_error(&self.path))?;
+
+        tx.commit().chain_err(|| db_error(&self.path))
+    }
+}
+
+/// Bring the layout of the database up to date. Each step is applied in its
+/// own transaction, together with the new version number
+fn migrate(conn: &Connection, path: &Path) -> Result<()> {
+    let version: i32 = conn.query_row("PRAGMA user_version", &[], |row| row.get(0))
+        .chain_err(|| db_error(path))?;
+
+    // Written by a newer version of the SDK
+    if version < 0 || version as usize > MIGRATIONS.len() {
+        bail!(
+            "Database {:?} has version {}, only version {} is supported",
+            path,
+            version,
+            MIGRATIONS.len()
+        );
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        conn.execute_batch(&format!(
+            "BEGIN IMMEDIATE; {} PRAGMA user_version = {}; COMMIT;",
+            migration,
+            from + 1
+        )).chain_err(|| format!("Failed to upgrade database {:?}", path))?;
+    }
+
+    Ok(())
+}
+
+/// Split a saved document into records. Each entry of a map within `data` is a
+/// record, keyed by `<field>/<key>`. The rest is kept in the root record. Contents
+/// which are not a document are kept as they are, to be reported as corrupt when loaded
+fn split_records(contents: &[u8]) -> BTreeMap<String, Vec<u8>> {
+    let mut records = BTreeMap::new();
+
+    let mut document: Value = match serde_json::from_slice(contents) {
+        Ok(document) => document,
+        Err(_) => {
+            records.insert(ROOT_KEY.into(), contents.to_vec());
+            return records;
+        }
+    };
+
+    if let Some(&mut Value::Object(ref mut data)) = document.get_mut("data") {
+        for (field, value) in data.iter_mut() {
+            if let Value::Object(ref mut entries) = *value {
+                for (key, entry) in ::std::mem::replace(entries, Map::new()) {
+                    records.insert(format!("{}/{}", field, key), entry.to_string().into_bytes());
+                }
+            }
+        }
+    }
+
+    records.insert(ROOT_KEY.into(), document.to_string().into_bytes());
+    records
+}
+
+/// Reassemble a document saved by `split_records`
+fn join_records(mut records: BTreeMap<String, Vec<u8>>) -> Result<Vec<u8>> {
+    let root = records
+        .remove(ROOT_KEY)
+        .ok_or_else(|| Error::from("Missing root record"))?;
+
+    if records.is_empty() {
+        return Ok(root);
+    }
+
+    let mut document: Value =
+        serde_json::from_slice(&root).chain_err(|| "Unexpected root record")?;
+
+    for (path, contents) in records {
+        let mut parts = path.splitn(2, '/');
+        let (field, key) = match (parts.next(), parts.next()) {
+            (Some(field), Some(key)) => (field, key),
+            _ => bail!("Unexpected record {:?}", path),
+        };
+
+        let entry: Value = serde_json::from_slice(&contents)
+            .chain_err(|| format!("Unexpected record {:?}", path))?;
+
+        match document.get_mut("data").and_then(|data| data.get_mut(field)) {
+            Some(&mut Value::Object(ref mut entries)) => {
+                entries.insert(key.into(), entry);
+            }
+            _ => bail!("Record {:?} does not belong to a map", path),
+        }
+    }
+
+    Ok(document.to_string().into_bytes())
+}
+
+fn db_error(path: &Path) -> String {
+    format!("Database error on {:?}", path)
+}
@@ -0,0 +1,130 @@
+// Copyright 2017 Telefónica Germany Next GmbH. See the COPYRIGHT file at
+// the top-level directory of this distribution
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+
+use errors::*;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"
+];
+
+/// Determine when a PEM encoded certificate expires, in seconds since the unix epoch
+pub fn expiry(pem: &[u8]) -> Result<u64> {
+    let cert = X509::from_pem(pem).chain_err(|| "Failed to parse certificate")?;
+    let not_after = cert.not_after().to_string();
+
+    parse_time(&not_after)
+        .ok_or_else(|| format!("Unexpected certificate expiry {:?}", not_after).into())
+}
+
+/// Make sure a secret can be parsed. Keys ending in `.key` hold a PEM encoded
+/// private key, all others a PEM encoded certificate
+pub fn validate(key: &str, pem: &[u8]) -> Result<()> {
+    if key.ends_with(".key") {
+        PKey::private_key_from_pem(pem).chain_err(|| "Failed to parse private key")?;
+    } else {
+        X509::from_pem(pem).chain_err(|| "Failed to parse certificate")?;
+    }
+
+    Ok(())
+}
+
+/// Parse a time as printed by OpenSSL, e.g. `Jun  1 12:00:00 2018 GMT`
+fn parse_time(time: &str) -> Option<u64> {
+    let parts: Vec<&str> = time.split_whitespace().collect();
+    if parts.len() != 5 || parts[4] != "GMT" {
+        return None;
+    }
+
+    let month = MONTHS.iter().position(|m| *m == parts[0])? as i64 + 1;
+    let day: i64 = parts[1].parse().ok()?;
+    let year: i64 = parts[3].parse().ok()?;
+
+    let hms = parts[2]
+        .split(':')
+        .map(|p| p.parse::<u64>().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    if hms.len() != 3 || !(1..=31).contains(&day) || hms[0] > 23 || hms[1] > 59 || hms[2] > 60 {
+        return None;
+    }
+
+    let days = days_since_epoch(year, month, day);
+    if days < 0 {
+        return None;
+    }
+
+    Some(days as u64 * 24 * 60 * 60 + hms[0] * 60 * 60 + hms[1] * 60 + hms[2])
+}
+
+/// Days between the unix epoch and a date of the gregorian calendar, see
+/// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_since_epoch(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{days_since_epoch, parse_time};
+
+    #[test]
+    fn parses_openssl_times() {
+        assert_eq!(parse_time("Jan  1 00:00:00 1970 GMT"), Some(0));
+        assert_eq!(parse_time("Jun  1 12:00:00 2018 GMT"), Some(1_527_854_400));
+        assert_eq!(parse_time("Feb 29 00:00:00 2000 GMT"), Some(951_782_400));
+        assert_eq!(parse_time("Jan  1 00:00:00 2050 GMT"), Some(2_524_608_000));
+
+        // Certificates which never expire
+        assert_eq!(parse_time("Dec 31 23:59:59 9999 GMT"), Some(253_402_300_799));
+    }
+
+    #[test]
+    fn rejects_unexpected_times() {
+        assert_eq!(parse_time(""), None);
+        assert_eq!(parse_time("Jun  1 12:00:00 2018"), None);
+        assert_eq!(parse_time("Jun  1 12:00:00 2018 UTC"), None);
+        assert_eq!(parse_time("June  1 12:00:00 2018 GMT"), None);
+        assert_eq!(parse_time("Jun  1 12:00 2018 GMT"), None);
+        assert_eq!(parse_time("Jun  1 12:00:00:00 2018 GMT"), None);
+        assert_eq!(parse_time("Jun  0 12:00:00 2018 GMT"), None);
+        assert_eq!(parse_time("Jun 32 12:00:00 2018 GMT"), None);
+        assert_eq!(parse_time("Jun  1 24:00:00 2018 GMT"), None);
+        assert_eq!(parse_time("Jun  1 12:60:00 2018 GMT"), None);
+        assert_eq!(parse_time("Jun  1 12:00:00 20l8 GMT"), None);
+
+        // Before the unix epoch
+        assert_eq!(parse_time("Dec 31 23:59:59 1969 GMT"), None);
+    }
+
+    #[test]
+    fn counts_days_since_epoch() {
+        assert_eq!(days_since_epoch(1970, 1, 1), 0);
+        assert_eq!(days_since_epoch(1969, 12, 31), -1);
+        assert_eq!(days_since_epoch(2000, 3, 1), 11_017);
+        assert_eq!(days_since_epoch(1600, 1, 1), -135_140);
+    }
+
+    #[test]
+    fn counts_leap_days() {
+        // Divisible by 4
+        assert_eq!(days_since_epoch(2016, 3, 1) - days_since_epoch(2016, 2, 28), 2);
+        assert_eq!(days_since_epoch(2017, 3, 1) - days_since_epoch(2017, 2, 28), 1);
+
+        // Divisible by 100, but not by 400
+        assert_eq!(days_since_epoch(2100, 3, 1) - days_since_epoch(2100, 2, 28), 1);
+
+        // Divisible by 400
+        assert_eq!(days_since_epoch(2000, 3, 1) - days_since_epoch(2000, 2, 28), 2);
+    }
+}
@@ -1,12 +1,16 @@
-use std::collections::HashMap;
-use std::sync::mpsc::Sender;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
 
 use log;
 use uuid::Uuid;
 use geeny_api::models::ThingRequest;
 
 use errors::*;
-use things_db::PartialThingMessage;
+use secrets::{self, SecretStore};
+use storage::{self, Document, Migration};
+use things_db::{CreateThingResult, CreateThingStatus, PartialThingMessage, ThingInfo};
+use things_db::outbox::{Outbox, OutboxSettings};
 use things_db::state::ThingSyncState;
 use things_db::runner::CarePackage;
 use things_db::hub_thing::HubThing;
@@ -18,6 +22,20 @@ pub struct ThingDb {
 
     // secondary is Geeny id
     secondary: HashMap<Uuid, String>,
+
+    #[serde(skip)]
+    outbox: OutboxSettings,
+
+    #[serde(skip)]
+    secrets: Option<Arc<SecretStore>>,
+}
+
+// Saved in the element file. Please add a migration whenever the serialized
+// layout of `ThingDb`, `HubThing`, `ThingSyncState` or `MetaThing` changes
+impl Document for ThingDb {
+    fn migrations() -> Vec<Migration> {
+        vec![storage::add_version]
+    }
 }
 
 // Internal data structure-y things
@@ -30,8 +48,10 @@ impl ThingDb {
 
     fn insert_secondary(&mut self, pkey: String, skey: Uuid) -> Result<()> {
         if self.primary.contains_key(&pkey) {
-            if let Some(old_pkey) = self.secondary.insert(skey, pkey) {
-                log::warn!("Unexpected secondary insert, replacing {}", old_pkey);
+            if let Some(old_pkey) = self.secondary.insert(skey, pkey.clone()) {
+                if old_pkey != pkey {
+                    log::warn!("Unexpected secondary insert, replacing {}", old_pkey);
+                }
             }
             Ok(())
         } else {
@@ -62,39 +82,92 @@ impl ThingDb {
 
     fn receive_from_cloud(&self, pkey: &str) -> Result<Vec<PartialThingMessage>> {
         if let Some(m) = self.primary.get(pkey) {
-            Ok(m.modem.cloud_to_hub_receiver.try_iter().collect())
+            Ok(m.modem.inbox.drain())
         } else {
-            bail!(
-                "Tried to receive from cloud for s/n {}, does not exist",
-                pkey
-            )
+            bail!(ErrorKind::UnknownSerial(pkey.into()))
         }
     }
 
-    fn sender_to_cloud(&self, pkey: &str) -> Result<Sender<PartialThingMessage>> {
-        if let Some(m) = self.primary.get(pkey) {
-            Ok(m.modem.hub_to_cloud_sender.clone())
+    fn send_to_cloud(&mut self, pkey: &str, msgs: &[PartialThingMessage]) -> Result<()> {
+        let settings = &self.outbox;
+        if let Some(m) = self.primary.get_mut(pkey) {
+            m.outbox(pkey, settings)?.push(msgs)?;
+
+            // Send the new messages right away
+            m.wake();
+            Ok(())
         } else {
-            bail!(
-                "Tried to get sender from hub for s/n {}, does not exist",
-                pkey
-            )
+            bail!(ErrorKind::UnknownSerial(pkey.into()))
         }
     }
 
     fn contains_primary(&self, pkey: &str) -> bool {
         self.primary.contains_key(pkey)
     }
+
+    fn primary_for_secondary(&self, skey: &Uuid) -> Option<&String> {
+        self.secondary.get(skey)
+    }
 }
 
 // Public interface
 impl ThingDb {
-    pub fn manage(&mut self, package: CarePackage) {
+    /// Set the location and limits of the message outboxes. Must be called before
+    /// messages are sent
+    pub fn set_outbox_settings(&mut self, settings: OutboxSettings) {
+        self.outbox = settings;
+    }
+
+    /// Set the store holding the certificates of each thing. Must be called before
+    /// things are unpaired, so that their certificates can be removed
+    pub fn set_secret_store(&mut self, secrets: Arc<SecretStore>) {
+        self.secrets = Some(secrets);
+    }
+
+    /// Manage all things which are due. Returns the time at which things need to
+    /// be managed next, if any
+    pub fn manage(&mut self, package: CarePackage) -> Option<Instant> {
         let mut new_uuid_pairs = vec![];
+        let mut failed = vec![];
+        let now = Instant::now();
 
         for (serial, doppel) in &mut self.primary {
-            match doppel.manage(&package) {
-                Err(e) => log::error!("Error in mgmt: {}", e),
+            let due = doppel
+                .modem
+                .schedule
+                .deadline(now)
+                .map(|at| at <= now)
+                .unwrap_or(false);
+            if !due {
+                continue;
+            }
+
+            // Make sure messages queued by a previous run are picked up
+            if let Err(e) = doppel.outbox(serial, &self.outbox) {
+                log::error!("Failed to open outbox for {}: {}", serial, e);
+            }
+
+            let before = doppel.info(serial);
+            let managed = doppel.manage(&package);
+
+            // Let waiters, see `HubSDK::wait_for_active`, know about progress and failures
+            let after = doppel.info(serial);
+            if after.state != before.state || after.thing_id != before.thing_id ||
+                after.mqtt_client_started != before.mqtt_client_started ||
+                after.last_failure != before.last_failure
+            {
+                package.config.states.publish(serial, &after);
+            }
+
+            match managed {
+                Err(e) => {
+                    log::error!("Error in mgmt: {}", e);
+
+                    // A thing which has given up no longer has a Geeny Thing ID
+                    if doppel.thing.thing_id().is_none() {
+                        failed.push(serial.clone());
+                    }
+                }
                 Ok(Some(uuid)) => {
                     new_uuid_pairs.push((uuid, serial.clone()));
                 }
@@ -102,33 +175,138 @@ impl ThingDb {
             }
         }
 
+        if !failed.is_empty() {
+            self.secondary.retain(|_, s| !failed.contains(s));
+        }
+
         for (uuid, serial) in new_uuid_pairs.drain(..) {
+            // A thing gets a new Geeny Thing ID when its certificates are rotated
+            self.secondary.retain(|_, s| *s != serial);
+
             if let Err(e) = self.insert_secondary(serial, uuid) {
                 log::error!("{}", e);
             }
         }
+
+        self.next_deadline()
+    }
+
+    /// The earliest time at which a thing needs to be managed, if any
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let now = Instant::now();
+        self.primary
+            .values()
+            .filter_map(|doppel| doppel.modem.schedule.deadline(now))
+            .min()
+    }
+
+    /// Make sure all things are managed as soon as possible, e.g. after a login
+    pub fn wake_all(&mut self) {
+        for doppel in self.primary.values_mut() {
+            doppel.wake();
+        }
+    }
+
+    /// Make sure the certificates of all active things are usable, provisioning
+    /// things with missing or damaged certificates again. Certificates which can't
+    /// be read, e.g. because the secret store can't decrypt them, are left alone.
+    /// Certificates of things which are no longer paired are removed
+    pub fn check_certificates(&mut self) {
+        let secrets = match self.secrets {
+            Some(ref secrets) => secrets.clone(),
+            None => return,
+        };
+
+        let mut reprovisioned = vec![];
+        for (serial, doppel) in &mut self.primary {
+            match doppel.thing.verify_certificates(&*secrets) {
+                Ok(None) => {}
+                Ok(Some(problem)) => {
+                    log::error!(
+                        "Unusable certificates for {}, provisioning again: {}",
+                        serial,
+                        problem
+                    );
+                    doppel.thing.reprovision();
+                    reprovisioned.push(serial.clone());
+                }
+                Err(e) => {
+                    log::error!("Failed to check certificates of {}, keeping them: {}", serial, e);
+                }
+            }
+        }
+
+        // The thing will get a new Geeny Thing ID
+        for serial in reprovisioned {
+            self.secondary.retain(|_, s| *s != serial);
+        }
+
+        let paired: HashSet<Uuid> = self.primary
+            .values()
+            .filter_map(|doppel| doppel.thing.thing_id())
+            .collect();
+
+        let keys = match secrets.keys() {
+            Ok(keys) => keys,
+            Err(e) => {
+                log::error!("Failed to list secrets: {}", e);
+                return;
+            }
+        };
+
+        for key in keys {
+            let stale = secrets::certificate_owner(&key)
+                .map(|id| !paired.contains(&id))
+                .unwrap_or(false);
+
+            if stale {
+                log::info!("Removing certificate {} of an unpaired thing", key);
+                if let Err(e) = secrets.delete(&key) {
+                    log::error!("Failed to remove {}: {}", key, e);
+                }
+            }
+        }
+    }
+
+    /// Disconnect all things from the Geeny cloud, keeping them paired
+    pub fn disconnect_all(&mut self) {
+        for doppel in self.primary.values_mut() {
+            doppel.thing.disconnect();
+        }
     }
 
     pub fn unpair_all(&mut self) {
         self.secondary.clear();
 
-        for (_, doppel) in self.primary.drain() {
-            doppel.extract().consume();
+        for (serial, doppel) in self.primary.drain() {
+            doppel.extract().consume(self.secrets.as_ref().map(|s| &**s));
+
+            if let Err(e) = Outbox::remove(&self.outbox, &serial) {
+                log::error!("{}", e);
+            }
         }
     }
 
     pub fn unpair(&mut self, serial_number: &str) -> Result<()> {
         let to_delete = self.remove_by_primary(serial_number)
-            .ok_or_else(|| Error::from("No device with that serial number found."))?;
+            .ok_or_else(|| Error::from(ErrorKind::UnknownSerial(serial_number.into())))?;
 
-        to_delete.extract().consume();
+        to_delete.extract().consume(self.secrets.as_ref().map(|s| &**s));
 
-        Ok(())
+        Outbox::remove(&self.outbox, serial_number)
     }
 
+    /// Add a thing. A `Failed` thing with the same serial number is created again
     pub fn add_thing(&mut self, new_thing: ThingRequest) -> Result<()> {
-        if self.contains_primary(&new_thing.serial_number) {
-            bail!("Duplicate device!")
+        check_request(&new_thing)?;
+
+        if let Some(doppel) = self.primary.get_mut(&new_thing.serial_number) {
+            if !doppel.thing.is_failed() {
+                bail!(ErrorKind::DuplicateThing(new_thing.serial_number))
+            }
+
+            doppel.restart(new_thing);
+            return Ok(());
         }
 
         self.insert_primary(
@@ -138,22 +316,158 @@ impl ThingDb {
         Ok(())
     }
 
+    /// Add several things. Each request is accepted or refused on its own
+    pub fn add_things(&mut self, requests: Vec<ThingRequest>) -> Result<Vec<CreateThingResult>> {
+        let mut results = vec![];
+
+        for request in requests {
+            let serial_number = request.serial_number.clone();
+
+            let (status, reason) = match self.add_thing(request) {
+                Ok(()) => (CreateThingStatus::Accepted, None),
+                Err(e) => match refusal(&e) {
+                    Some(refused) => refused,
+                    None => return Err(e),
+                },
+            };
+
+            results.push(CreateThingResult {
+                serial_number: serial_number,
+                status: status,
+                reason: reason,
+            });
+        }
+
+        Ok(results)
+    }
+
     pub fn contains_serial(&self, serial_number: &str) -> bool {
         self.contains_primary(serial_number)
     }
 
-    pub fn hub_tx(&mut self, serial_number: &str, msgs: &[PartialThingMessage]) -> Result<()> {
-        let cloud_sender = self.sender_to_cloud(serial_number)?;
+    /// Find the serial number of a thing by its Geeny Thing ID. Things can only be
+    /// found once they have been created on the Geeny cloud
+    pub fn serial_for_id(&self, thing_id: &Uuid) -> Result<String> {
+        self.primary_for_secondary(thing_id)
+            .cloned()
+            .ok_or_else(|| ErrorKind::UnknownThingId(*thing_id).into())
+    }
 
-        for msg in msgs {
-            cloud_sender
-                .send(msg.clone())
-                .chain_err(|| "Failed to send! Other side hung up?")?;
-        }
+    pub fn list(&self) -> Vec<ThingInfo> {
+        let mut things: Vec<ThingInfo> = self.primary
+            .iter()
+            .map(|(serial, doppel)| doppel.info(serial))
+            .collect();
+
+        things.sort_by(|a, b| a.serial_number.cmp(&b.serial_number));
+        things
+    }
+
+    pub fn info(&self, serial_number: &str) -> Result<ThingInfo> {
+        self.primary
+            .get(serial_number)
+            .map(|doppel| doppel.info(serial_number))
+            .ok_or_else(|| ErrorKind::UnknownSerial(serial_number.into()).into())
+    }
+
+    /// Request new certificates for an active thing. Queued messages are kept, and
+    /// sent once the thing is connected with the new certificates
+    pub fn rotate_certificates(&mut self, serial_number: &str) -> Result<()> {
+        let doppel = self.primary
+            .get_mut(serial_number)
+            .ok_or_else(|| Error::from(ErrorKind::UnknownSerial(serial_number.into())))?;
+
+        doppel.thing.start_rotation(serial_number)?;
+        doppel.wake();
         Ok(())
     }
 
+    pub fn hub_tx(&mut self, serial_number: &str, msgs: &[PartialThingMessage]) -> Result<()> {
+        self.send_to_cloud(serial_number, msgs)
+    }
+
     pub fn hub_rx(&mut self, serial_number: &str) -> Result<Vec<PartialThingMessage>> {
         self.receive_from_cloud(serial_number)
     }
 }
+
+fn check_request(request: &ThingRequest) -> Result<()> {
+    let reason = if request.serial_number.trim().is_empty() {
+        "the serial number is empty"
+    } else if request.name.trim().is_empty() {
+        "the name is empty"
+    } else if request.thing_type.is_nil() {
+        "the thing type is missing"
+    } else {
+        return Ok(());
+    };
+
+    bail!(ErrorKind::InvalidThingRequest(reason.into()))
+}
+
+// How a single request of a batch is refused. Other errors fail the whole batch
+fn refusal(error: &Error) -> Option<(CreateThingStatus, Option<String>)> {
+    match *error.kind() {
+        ErrorKind::DuplicateThing(_) => Some((CreateThingStatus::Duplicate, None)),
+        ErrorKind::InvalidThingRequest(ref reason) => {
+            Some((CreateThingStatus::Invalid, Some(reason.clone())))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+    use geeny_api::models::ThingRequest;
+
+    use super::ThingDb;
+    use errors::*;
+    use things_db::{CreateThingStatus, ThingState};
+
+    fn request(serial_number: &str, name: &str) -> ThingRequest {
+        ThingRequest {
+            name: name.into(),
+            serial_number: serial_number.into(),
+            thing_type: Uuid::new_v4(),
+        }
+    }
+
+    #[test]
+    fn single_incomplete_request_is_invalid() {
+        let mut db = ThingDb::default();
+
+        match db.add_thing(request("ABC123456", " ")) {
+            Err(Error(ErrorKind::InvalidThingRequest(_), _)) => {}
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(()) => panic!("Accepted a thing without a name"),
+        }
+        assert!(db.info("ABC123456").is_err());
+
+        db.add_thing(request("ABC123456", "Demo Thing"))
+            .expect("Refused a complete request");
+        assert_eq!(db.info("ABC123456").unwrap().state, ThingState::Created);
+    }
+
+    #[test]
+    fn batch_refuses_requests_individually() {
+        let mut db = ThingDb::default();
+
+        let results = db.add_things(vec![
+            request("ABC123456", "Demo Thing"),
+            request("", "Demo Thing"),
+            request("ABC123456", "Demo Thing"),
+        ]).expect("Failed to add things");
+
+        let statuses = results.iter().map(|r| r.status).collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            vec![
+                CreateThingStatus::Accepted,
+                CreateThingStatus::Invalid,
+                CreateThingStatus::Duplicate,
+            ]
+        );
+        assert!(results[1].reason.is_some());
+    }
+}
@@ -1,18 +1,181 @@
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant};
 
 use log;
+use ring::rand::{SecureRandom, SystemRandom};
 use uuid::Uuid;
+use geeny_api::models::ThingRequest;
 
 use errors::*;
-use things_db::PartialThingMessage;
-use things_db::state::ThingSyncState;
+use interface::RetryConfig;
+use things_db::{ThingFailure, ThingInfo};
+use things_db::inbox::Inbox;
+use things_db::outbox::{self, Outbox, OutboxSettings};
+use things_db::state::{self, ThingSyncState};
 use things_db::runner::CarePackage;
 
+// Other failed steps, such as publishing queued messages, are retried after this delay
+const RETRY_SECS: u64 = 5;
+
+// Connected things are checked this often, e.g. for expiring certificates
+const IDLE_CHECK_SECS: u64 = 60 * 60; // One hour
+
+/// When a `HubThing` needs to be managed next
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Schedule {
+    /// As soon as possible
+    Due,
+
+    /// Once the given time has passed
+    At(Instant),
+
+    /// Only once woken, e.g. by a new login
+    Idle,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule::Due
+    }
+}
+
+impl Schedule {
+    fn after_secs(secs: u64) -> Self {
+        Schedule::At(Instant::now() + Duration::from_secs(secs))
+    }
+
+    /// The time at which the thing needs to be managed, if any
+    pub fn deadline(&self, now: Instant) -> Option<Instant> {
+        match *self {
+            Schedule::Due => Some(now),
+            Schedule::At(at) => Some(at),
+            Schedule::Idle => None,
+        }
+    }
+}
+
+/// Failed attempts to create or connect a thing, since the last successful step
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RetryState {
+    /// Number of failed attempts
+    pub attempts: u32,
+
+    /// Number of failed attempts which are not expected to succeed when repeated,
+    /// e.g. because the request was rejected
+    pub permanent_failures: u32,
+
+    /// Description of the most recent failure
+    pub last_error: Option<String>,
+
+    /// Time of the most recent failure, in seconds since the unix epoch
+    pub last_failure_at: Option<u64>,
+
+    /// Time of the next attempt, in seconds since the unix epoch
+    pub next_attempt_at: Option<u64>,
+}
+
+impl RetryState {
+    /// Record a failed attempt, returning the delay before the next attempt
+    fn record(&mut self, error: &Error, permanent: bool, config: &RetryConfig) -> Duration {
+        self.attempts = self.attempts.saturating_add(1);
+        if permanent {
+            self.permanent_failures = self.permanent_failures.saturating_add(1);
+        }
+        let now = outbox::now_secs();
+        let delay = backoff(self.attempts, config);
+
+        self.last_error = Some(describe(error));
+        self.last_failure_at = Some(now);
+        self.next_attempt_at = Some(now + delay.as_secs());
+
+        delay
+    }
+
+    /// The most recent failure, unless a step succeeded since
+    fn failure(&self) -> Option<ThingFailure> {
+        Some(ThingFailure {
+            reason: self.last_error.clone()?,
+            failed_at: self.last_failure_at.unwrap_or(0),
+            attempts: self.attempts,
+            next_attempt_at: self.next_attempt_at,
+        })
+    }
+
+    /// Whether too many permanent errors occurred to keep trying
+    fn exhausted(&self, config: &RetryConfig) -> bool {
+        config.max_permanent_failures > 0 &&
+            self.permanent_failures >= config.max_permanent_failures
+    }
+}
+
+/// Only requests the Geeny cloud explicitly rejected are not expected to succeed
+/// when repeated. Outages, network errors and tokens which are about to be
+/// refreshed go away by themselves
+fn is_permanent(error: &Error) -> bool {
+    match *error.kind() {
+        ErrorKind::CloudRejected(_) => true,
+        _ => false,
+    }
+}
+
+/// Errors which are certain to occur again, the thing is `Failed` right away
+fn is_final(error: &Error) -> bool {
+    match *error.kind() {
+        ErrorKind::ThingExists(..) => true,
+        _ => false,
+    }
+}
+
+/// The error, including its causes, e.g. "Failed to connect!: Connection refused"
+fn describe(error: &Error) -> String {
+    error
+        .iter()
+        .map(|cause| cause.to_string())
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+/// Exponential backoff, randomly shortened by up to half of the delay
+fn backoff(attempts: u32, config: &RetryConfig) -> Duration {
+    let doublings = attempts.saturating_sub(1).min(31);
+    let secs = config
+        .initial_backoff_secs
+        .saturating_mul(1 << doublings)
+        .min(config.max_backoff_secs);
+
+    let millis = secs.saturating_mul(1000);
+    Duration::from_millis(millis - jitter(millis / 2))
+}
+
+/// A random number between zero and `max`, inclusive
+fn jitter(max: u64) -> u64 {
+    let mut bytes = [0u8; 8];
+    if max == 0 || SystemRandom::new().fill(&mut bytes).is_err() {
+        return 0;
+    }
+
+    let random = bytes.iter().fold(0u64, |n, b| (n << 8) | u64::from(*b));
+    random % (max + 1)
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct HubThing {
     pub thing: ThingSyncState,
 
+    // Kept across restarts, so that a restart does not reset the backoff
+    #[serde(default)]
+    pub retry: RetryState,
+
+    // Geeny Thing ID replaced by a certificate rotation. The replaced thing is
+    // only deleted once the new thing has been saved, i.e. the next time this
+    // thing is managed, so that a crash can't lose both
+    #[serde(default)]
+    pub replaced: Option<Uuid>,
+
+    // Geeny Thing ID before the most recent certificate rotation
+    #[serde(default)]
+    pub previous_thing_id: Option<Uuid>,
+
     #[serde(skip)]
     pub modem: HubModem,
 }
@@ -21,6 +184,9 @@ impl HubThing {
     pub fn new(thing: ThingSyncState) -> Self {
         Self {
             thing: thing,
+            retry: RetryState::default(),
+            replaced: None,
+            previous_thing_id: None,
             modem: HubModem::default(),
         }
     }
@@ -29,40 +195,95 @@ impl HubThing {
         use self::ThingSyncState::*;
 
         let mut retval = None;
+        let mut connect_error = None;
+        let mut rotated_from = None;
+
+        // The new thing has been saved since the rotation
+        self.delete_replaced(package);
+
+        // Unless the step succeeds, try again later
+        self.modem.schedule = Schedule::after_secs(RETRY_SECS);
 
-        let new_state = match (&mut self.thing, package.token_opt.as_ref()) {
+        let step = match (&mut self.thing, package.token_opt.as_ref()) {
             // A device has been created, and we have a valid token
             (&mut Created(ref req), Some(token)) => {
-                ThingSyncState::create_new_thing(&package.config.api, token, req)
+                Some(ThingSyncState::create_new_thing(
+                    &package.config.api,
+                    token,
+                    req,
+                    package.config.existing_thing_policy,
+                    &*package.config.secrets,
+                ))
             }
 
             // A device needs metadata, and we have a valid token
             (&mut GatheringMetadata(ref thing), Some(token)) => {
-                ThingSyncState::gather_thing_metadata(&package.config.api, token, thing)
+                Some(ThingSyncState::gather_thing_metadata(package, token, thing))
+            }
+
+            // A device needs new certificates, and we have a valid token
+            (&mut RotatingCertificates(ref thing), Some(token)) => {
+                rotated_from = Some(thing.id);
+                Some(ThingSyncState::rotate_certificates(&package.config.api, token, thing))
             }
 
             // A device has metadata, but needs an MQTT connection
             (&mut Active(ref mut active), _) if active.mqtt_handle.is_none() => {
-                active.connect_mqtt(
-                    self.modem.cloud_to_hub_sender.clone(),
-                    &package.config.certificate_storage,
+                let connected = active.connect_mqtt(
+                    self.modem.inbox.clone(),
+                    &*package.config.secrets,
                     &package.config.mqtt_host,
                     package.config.mqtt_port,
-                )?;
+                    &package.config.delivery,
+                    package.config.messages.clone(),
+                );
 
+                if connected.is_ok() {
+                    // Send any queued messages right away
+                    self.modem.schedule = Schedule::Due;
+                    self.retry = RetryState::default();
+                }
+                connect_error = connected.err();
                 None
             }
 
             // A device is doing business
             (&mut Active(ref mut active), _) => {
-                let packet_iter = self.modem.hub_to_cloud_receiver.try_iter();
+                active.warn_on_expiry();
 
-                if let Err(e) = active.process_messages(packet_iter) {
-                    log::error!("Error: {}", e);
+                let sent = match self.modem.outbox {
+                    Some(ref outbox) => active.process_messages(outbox, &package.config.delivery),
+                    None => Ok(()),
+                };
+
+                match sent {
+                    // Nothing to do until more messages are sent
+                    Ok(_) => self.modem.schedule = Schedule::after_secs(IDLE_CHECK_SECS),
+                    Err(e) => log::error!("Error: {}", e),
                 }
                 None
             }
-            _ => None,
+
+            // Nothing can be done until a user logs in, or a failed
+            // thing is created again
+            _ => {
+                self.modem.schedule = Schedule::Idle;
+                None
+            }
+        };
+
+        // Connection failures go away by themselves, e.g. once the network is back
+        if let Some(e) = connect_error {
+            return Err(self.failed(e, false, &package.config.retry));
+        }
+
+        let new_state = match step {
+            Some(Ok(state)) => Some(state),
+            Some(Err(e)) => {
+                let permanent = is_permanent(&e);
+                return Err(self.failed(e, permanent, &package.config.retry));
+            }
+            None => None,
         };
 
         // A transition occurred
@@ -70,35 +291,123 @@ impl HubThing {
             log::info!("Transition from {} to {}", self.thing, state);
 
             self.thing = state;
+            self.retry = RetryState::default();
+            retval = self.thing.thing_id();
 
-            if let Active(ref thing) = self.thing {
-                retval = Some(thing.thing.id);
+            if rotated_from.is_some() {
+                self.replaced = rotated_from;
+                self.previous_thing_id = rotated_from;
             }
+
+            // Continue with the next state right away
+            self.modem.schedule = Schedule::Due;
         }
         Ok(retval)
     }
 
+    /// Record a failed step, and schedule the next attempt. The thing enters the
+    /// `Failed` state once too many permanent errors occurred, or right away if
+    /// the error is final
+    fn failed(&mut self, error: Error, permanent: bool, config: &RetryConfig) -> Error {
+        let delay = self.retry.record(&error, permanent, config);
+
+        if is_final(&error) || (permanent && self.retry.exhausted(config)) {
+            log::error!(
+                "Giving up on {} after {} failed attempts",
+                self.thing,
+                self.retry.attempts
+            );
+            self.thing.fail();
+            self.retry.next_attempt_at = None;
+            self.modem.schedule = Schedule::Idle;
+        } else {
+            log::warn!("Retrying {} in {}s", self.thing, delay.as_secs());
+            self.modem.schedule = Schedule::At(Instant::now() + delay);
+        }
+
+        error
+    }
+
+    /// Delete the thing replaced by a certificate rotation, if any. Other than a
+    /// rejection by the Geeny cloud, e.g. because the thing is already gone, failures
+    /// are retried the next time the thing is managed
+    fn delete_replaced(&mut self, package: &CarePackage) {
+        let (thing_id, token) = match (self.replaced, package.token_opt.as_ref()) {
+            (Some(thing_id), Some(token)) => (thing_id, token),
+            _ => return,
+        };
+
+        let deleted = state::delete_replaced_thing(
+            &package.config.api,
+            token,
+            &thing_id,
+            &*package.config.secrets,
+        );
+
+        match deleted {
+            Ok(()) => self.replaced = None,
+            Err(ref e) if is_permanent(e) => {
+                log::error!("Failed to delete replaced thing {}, giving up: {}", thing_id, e);
+                self.replaced = None;
+            }
+            Err(ref e) => log::error!("Failed to delete replaced thing {}: {}", thing_id, e),
+        }
+    }
+
+    /// Summarize the current state of the thing, including its most recent failure
+    pub fn info(&self, serial_number: &str) -> ThingInfo {
+        ThingInfo {
+            previous_thing_id: self.previous_thing_id,
+            last_failure: self.retry.failure(),
+            ..self.thing.info(serial_number)
+        }
+    }
+
+    /// Create a `Failed` thing again, keeping its queued messages
+    pub fn restart(&mut self, request: ThingRequest) {
+        log::info!("Creating {} again", self.thing);
+
+        self.thing = ThingSyncState::Created(request);
+        self.retry = RetryState::default();
+        self.wake();
+    }
+
+    /// Make sure the thing is managed as soon as possible
+    pub fn wake(&mut self) {
+        self.modem.schedule = Schedule::Due;
+    }
+
     pub fn extract(self) -> ThingSyncState {
         self.thing
     }
+
+    /// Obtain the outbox of this thing, opening it on first use
+    pub fn outbox(&mut self, serial_number: &str, settings: &OutboxSettings) -> Result<&Outbox> {
+        if self.modem.outbox.is_none() {
+            self.modem.outbox = Some(Outbox::open(settings, serial_number)?);
+        }
+
+        // The outbox was opened above
+        Ok(self.modem.outbox.as_ref().unwrap())
+    }
 }
 
 pub struct HubModem {
-    pub cloud_to_hub_sender: Sender<PartialThingMessage>,
-    pub cloud_to_hub_receiver: Receiver<PartialThingMessage>,
-    pub hub_to_cloud_sender: Sender<PartialThingMessage>,
-    pub hub_to_cloud_receiver: Receiver<PartialThingMessage>,
+    // Messages from the cloud to the hub, until polled
+    pub inbox: Inbox,
+
+    // Messages from the hub to the cloud are persisted, see `HubThing::outbox`
+    pub outbox: Option<Outbox>,
+
+    pub schedule: Schedule,
 }
 
 impl Default for HubModem {
     fn default() -> Self {
-        let (cth_tx, cth_rx) = channel();
-        let (htc_tx, htc_rx) = channel();
         Self {
-            cloud_to_hub_sender: cth_tx,
-            cloud_to_hub_receiver: cth_rx,
-            hub_to_cloud_sender: htc_tx,
-            hub_to_cloud_receiver: htc_rx,
+            inbox: Inbox::default(),
+            outbox: None,
+            schedule: Schedule::default(),
         }
     }
 }
@@ -0,0 +1,118 @@
+// Copyright 2017 Telefónica Germany Next GmbH. See the COPYRIGHT file at
+// the top-level directory of this distribution
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use log;
+
+use broadcast::Broadcast;
+use things_db::{PartialThingMessage, ThingMessage};
+
+// Messages kept for `HubSDK::receive_messages`. Once full, the oldest message
+// is dropped for each new one
+const INBOX_CAPACITY: usize = 1024;
+
+/// Messages from the Geeny cloud waiting to be polled with `HubSDK::receive_messages`.
+/// Cloning the `Inbox` shares the queued messages
+#[derive(Clone, Default)]
+pub struct Inbox {
+    messages: Arc<Mutex<VecDeque<PartialThingMessage>>>,
+}
+
+impl Inbox {
+    fn lock(&self) -> MutexGuard<'_, VecDeque<PartialThingMessage>> {
+        // Messages are only added or removed as a whole, a panic can't leave the
+        // queue inconsistent
+        self.messages.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Queue a message, dropping the oldest message if the inbox is full
+    pub fn push(&self, message: PartialThingMessage) {
+        let mut messages = self.lock();
+
+        if messages.len() >= INBOX_CAPACITY {
+            log::warn!("Messages are not being received, dropping the oldest message");
+            messages.pop_front();
+        }
+        messages.push_back(message);
+    }
+
+    /// Remove and return all queued messages, oldest first
+    pub fn drain(&self) -> Vec<PartialThingMessage> {
+        self.lock().drain(..).collect()
+    }
+}
+
+/// Deliver a message from the Geeny cloud to all subscribers of the thing, and
+/// queue it for polling with `HubSDK::receive_messages`
+pub fn deliver(
+    subscribers: &Broadcast<ThingMessage>,
+    inbox: &Inbox,
+    serial_number: &str,
+    message: PartialThingMessage,
+) {
+    subscribers.publish(
+        serial_number,
+        &ThingMessage {
+            serial_number: serial_number.into(),
+            msg: message.clone(),
+        },
+    );
+
+    inbox.push(message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{deliver, Inbox, INBOX_CAPACITY};
+    use broadcast::Broadcast;
+    use things_db::PartialThingMessage;
+
+    fn message(topic: &str) -> PartialThingMessage {
+        PartialThingMessage {
+            topic: topic.into(),
+            msg: b"payload".to_vec(),
+            qos: None,
+            retain: None,
+        }
+    }
+
+    #[test]
+    fn subscriber_and_poller_receive_the_same_message() {
+        let subscribers = Broadcast::default();
+        let subscription = subscribers.subscribe(Some("ABC123456".into()));
+        let inbox = Inbox::default();
+
+        deliver(&subscribers, &inbox, "ABC123456", message("demo/recv"));
+
+        let streamed = subscription.try_recv().expect("Subscriber missed the message");
+        assert_eq!(streamed.serial_number, "ABC123456");
+        assert_eq!(streamed.msg.topic, "demo/recv");
+
+        let polled = inbox.drain();
+        assert_eq!(polled.len(), 1);
+        assert_eq!(polled[0].topic, "demo/recv");
+        assert_eq!(polled[0].msg, streamed.msg.msg);
+
+        // Each message is polled once
+        assert!(inbox.drain().is_empty());
+    }
+
+    #[test]
+    fn full_inbox_drops_the_oldest_message() {
+        let inbox = Inbox::default();
+        for n in 0..INBOX_CAPACITY + 2 {
+            inbox.push(message(&n.to_string()));
+        }
+
+        let polled = inbox.drain();
+        assert_eq!(polled.len(), INBOX_CAPACITY);
+        assert_eq!(polled[0].topic, "2");
+        assert_eq!(polled[INBOX_CAPACITY - 1].topic, (INBOX_CAPACITY + 1).to_string());
+    }
+}
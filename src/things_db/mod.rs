@@ -18,28 +18,54 @@ mod core;
 // as well as the channels used to send data hub -> cloud and cloud -> hub
 mod hub_thing;
 
-// The `runner` module is a manually pumped event loop. It periodically prompts
-// the `ThingDb` to trigger each of its `HubThing`s to perform any necessary actions,
-// such as processing queued messages, or to update state if new information is available
+// The `runner` module is an event driven loop. It prompts the `ThingDb` to trigger
+// each of its `HubThing`s to perform any necessary actions, such as processing queued
+// messages, or to update state if new information is available. It sleeps until a
+// `HubThing` is due, or until woken by new messages, new things or a changed token
 mod runner;
 
 /// The `state` module contains the inner state types used by `HubThing`s.
 /// State transition logic occurs here
 mod state;
 
+// The `outbox` module contains the persistent queue of messages waiting to be
+// sent to the cloud. Each `HubThing` has its own outbox, stored next to the
+// element file, so messages survive restarts and periods without a connection
+mod outbox;
+
+// The `inbox` module contains the queue of messages from the cloud waiting to be
+// received by polling. Messages are queued whether or not there are subscribers
+mod inbox;
+
+// The `certificate` module inspects the X.509 certificates issued by the Geeny
+// cloud, e.g. to tell when they expire
+mod certificate;
+
 pub use self::runner::ThingDbRunner;
 pub use self::core::ThingDb;
 
+use geeny_api::models::Resource;
+use rumqtt;
+use uuid::Uuid;
+
 /// Structure to contain a message to be sent to or received from the Geeny Cloud
 ///
 /// Does not contain the Serial Number or `ThingId` of the device in question.
 ///
+/// The message payload is carried as raw bytes, and is passed to and from the
+/// MQTT connection unmodified. Binary formats such as CBOR or Protocol Buffers
+/// may be used, as well as plain text.
+///
+/// The MQTT Quality of Service level and retain flag may be set per message.
+/// When not set, the options configured for the matching resource are used,
+/// falling back to the defaults in `HubSDKConfig`.
+///
 /// Please see `HubSDK::send_messages` and `HubSDK::receive_messages` for further
 /// examples of usage
 ///
 /// ```rust,no_run
 /// use hub_sdk::{HubSDK, HubSDKConfig};
-/// use hub_sdk::services::PartialThingMessage;
+/// use hub_sdk::services::{PartialThingMessage, QoS};
 ///
 /// let sdk_cfg = HubSDKConfig::default();
 /// let hub_sdk = HubSDK::new(sdk_cfg);
@@ -48,18 +74,189 @@ pub use self::core::ThingDb;
 ///     PartialThingMessage {
 ///         topic: "demo/send/path".into(),
 ///         msg: "demonstration message".into(),
+///         ..Default::default()
 ///     },
 ///     PartialThingMessage {
 ///         topic: "demo/other/path".into(),
 ///         msg: "second demonstration message".into(),
+///         qos: Some(QoS::Level1),
+///         retain: Some(true),
 ///     },
 /// );
 ///
 /// hub_sdk.send_messages("ABC123456", &messages)
 ///     .expect("Failed to send messages!");
 /// ```
-#[derive(Debug, Serialize, Deserialize, Clone, Hash)]
+#[derive(Debug, Serialize, Deserialize, Clone, Hash, PartialEq, Default)]
 pub struct PartialThingMessage {
     pub topic: String,
-    pub msg: String,
+    pub msg: Vec<u8>,
+
+    #[serde(default)]
+    pub qos: Option<QoS>,
+
+    #[serde(default)]
+    pub retain: Option<bool>,
+}
+
+/// A message received from the Geeny Cloud, along with the Serial Number of
+/// the thing it was sent to
+///
+/// Please see `HubSDK::subscribe_messages` for further examples of usage
+#[derive(Debug, Serialize, Deserialize, Clone, Hash, PartialEq)]
+pub struct ThingMessage {
+    pub serial_number: String,
+    pub msg: PartialThingMessage,
+}
+
+/// Current state of a thing managed by the SDK
+///
+/// Please see `HubSDK::list_things` and `HubSDK::get_thing` for further
+/// examples of usage
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ThingInfo {
+    pub serial_number: String,
+
+    /// Geeny Thing ID, known once the thing has been created on the Geeny cloud
+    pub thing_id: Option<Uuid>,
+
+    pub state: ThingState,
+
+    /// Whether an MQTT client has been started for the thing. The client connects,
+    /// and reconnects, to the Geeny cloud in the background, this does not tell
+    /// whether it is connected right now
+    pub mqtt_client_started: bool,
+
+    /// Resources of the thing, known once the thing is `Active`
+    pub resources: Vec<Resource>,
+
+    /// Expiry of the client certificate in seconds since the unix epoch, known
+    /// once the thing is `Active`. Please see `HubSDK::rotate_certificates`
+    pub cert_expires_at: Option<u64>,
+
+    /// Geeny Thing ID before the most recent certificate rotation, if any. The
+    /// Geeny Thing ID changes when certificates are rotated, please see
+    /// `HubSDK::rotate_certificates`
+    pub previous_thing_id: Option<Uuid>,
+
+    /// The most recent failure to create or connect the thing, unless it made
+    /// progress since. Explains why a thing does not become `Active`
+    pub last_failure: Option<ThingFailure>,
+}
+
+/// A failed attempt to create a thing on the Geeny cloud, or to connect it via MQTT.
+/// Failed attempts are retried, unless the thing is `Failed`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ThingFailure {
+    /// Description of the failure, including its causes
+    pub reason: String,
+
+    /// Time of the failure in seconds since the unix epoch
+    pub failed_at: u64,
+
+    /// Number of consecutive failed attempts
+    pub attempts: u32,
+
+    /// Time of the next attempt in seconds since the unix epoch, if one is scheduled
+    pub next_attempt_at: Option<u64>,
+}
+
+/// Outcome of a single request passed to `HubSDK::create_things`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CreateThingResult {
+    pub serial_number: String,
+
+    pub status: CreateThingStatus,
+
+    /// Why the request is `Invalid`
+    pub reason: Option<String>,
+}
+
+/// Whether a thing passed to `HubSDK::create_things` will be created
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CreateThingStatus {
+    /// The thing is now managed by the SDK, and is created on the Geeny cloud
+    /// in the background. A `Failed` thing is created again
+    Accepted,
+
+    /// A thing with the same serial number is already managed by the SDK and
+    /// not `Failed`, or was requested earlier in the same batch
+    Duplicate,
+
+    /// The request is incomplete, see `CreateThingResult::reason`
+    Invalid,
+}
+
+/// Synchronization state of a thing with the Geeny cloud
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ThingState {
+    /// The thing has not yet been created (or found) on the Geeny cloud
+    Created,
+
+    /// The thing exists on the Geeny cloud, additional metadata is being gathered
+    GatheringMetadata,
+
+    /// All necessary information has been gathered, messages may be sent
+    Active,
+
+    /// New certificates have been requested, the thing is created on the
+    /// Geeny cloud again. Messages are queued until the thing is `Active`
+    RotatingCertificates,
+
+    /// The Geeny cloud rejected the thing too often, it is no longer managed.
+    /// Messages are queued until the thing is created again, please see
+    /// `HubSDK::create_thing`
+    Failed,
+}
+
+/// MQTT Quality of Service level, used for both publishing and subscribing
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QoS {
+    /// At most once delivery
+    Level0,
+
+    /// At least once delivery
+    Level1,
+
+    /// Exactly once delivery
+    Level2,
+}
+
+impl Default for QoS {
+    fn default() -> Self {
+        QoS::Level0
+    }
+}
+
+impl QoS {
+    /// Obtain a `QoS` from its numeric MQTT level (0, 1, or 2)
+    pub fn from_level(level: u8) -> Option<Self> {
+        match level {
+            0 => Some(QoS::Level0),
+            1 => Some(QoS::Level1),
+            2 => Some(QoS::Level2),
+            _ => None,
+        }
+    }
+}
+
+impl From<QoS> for rumqtt::QoS {
+    fn from(qos: QoS) -> Self {
+        match qos {
+            QoS::Level0 => rumqtt::QoS::Level0,
+            QoS::Level1 => rumqtt::QoS::Level1,
+            QoS::Level2 => rumqtt::QoS::Level2,
+        }
+    }
+}
+
+/// MQTT delivery options for a single resource. Options which are not set
+/// fall back to the defaults in `HubSDKConfig`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct DeliveryOptions {
+    #[serde(default)]
+    pub qos: Option<QoS>,
+
+    #[serde(default)]
+    pub retain: Option<bool>,
 }
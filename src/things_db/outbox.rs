@@ -0,0 +1,277 @@
+// Copyright 2017 Telefónica Germany Next GmbH. See the COPYRIGHT file at
+// the top-level directory of this distribution
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64;
+use log;
+use serde::{Deserialize, Deserializer, Serializer};
+use serde::de;
+
+use errors::*;
+use interface::{EvictionPolicy, OutboxConfig, StorageConfig};
+use storage::{self, Document, Migration, Store};
+use things_db::{PartialThingMessage, QoS};
+
+/// Location and limits shared by the outboxes of all things
+#[derive(Clone, Default)]
+pub struct OutboxSettings {
+    pub dir: PathBuf,
+    pub limits: OutboxConfig,
+    pub storage: StorageConfig,
+}
+
+impl OutboxSettings {
+    fn file_for(&self, serial_number: &str) -> PathBuf {
+        self.dir.join(format!("{}.outbox.json", escape_serial(serial_number)))
+    }
+
+    fn document_for(&self, serial_number: &str) -> String {
+        format!("outbox.{}", escape_serial(serial_number))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct OutboxEntry {
+    // Seconds since the unix epoch
+    queued_at: u64,
+
+    topic: String,
+
+    // Binary payloads take about four times the space as a JSON array of numbers
+    #[serde(serialize_with = "encode_payload", deserialize_with = "decode_payload")]
+    payload: Vec<u8>,
+
+    qos: Option<QoS>,
+    retain: Option<bool>,
+}
+
+impl OutboxEntry {
+    fn new(queued_at: u64, msg: &PartialThingMessage) -> Self {
+        OutboxEntry {
+            queued_at: queued_at,
+            topic: msg.topic.clone(),
+            payload: msg.msg.clone(),
+            qos: msg.qos,
+            retain: msg.retain,
+        }
+    }
+
+    fn message(&self) -> PartialThingMessage {
+        PartialThingMessage {
+            topic: self.topic.clone(),
+            msg: self.payload.clone(),
+            qos: self.qos,
+            retain: self.retain,
+        }
+    }
+}
+
+fn encode_payload<S>(payload: &[u8], serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&base64::encode(payload))
+}
+
+fn decode_payload<'de, D>(deserializer: D) -> ::std::result::Result<Vec<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let encoded = String::deserialize(deserializer)?;
+    base64::decode(&encoded).map_err(de::Error::custom)
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct OutboxData {
+    entries: VecDeque<OutboxEntry>,
+}
+
+// Queued messages are resent once the thing is connected, restoring an older
+// copy would send messages twice
+impl Document for OutboxData {
+    fn migrations() -> Vec<Migration> {
+        vec![storage::add_version]
+    }
+
+    fn backups() -> usize {
+        0
+    }
+}
+
+impl OutboxData {
+    /// Discard all messages older than `max_age` seconds, returning the number
+    /// of discarded messages
+    fn expire(&mut self, max_age: u64, now: u64) -> usize {
+        let before = self.entries.len();
+        self.entries
+            .retain(|e| now.saturating_sub(e.queued_at) <= max_age);
+        before - self.entries.len()
+    }
+
+    fn has_expired(&self, max_age: u64, now: u64) -> bool {
+        self.entries
+            .front()
+            .map(|e| now.saturating_sub(e.queued_at) > max_age)
+            .unwrap_or(false)
+    }
+}
+
+/// A persistent, ordered queue of messages waiting to be sent to the Geeny cloud
+/// on behalf of a single thing
+pub struct Outbox {
+    serial_number: String,
+    data: Store<OutboxData>,
+    limits: OutboxConfig,
+}
+
+impl Outbox {
+    /// Open the outbox for a thing, restoring any messages queued by a previous run.
+    /// An outbox that can't be read is quarantined, and replaced by an empty outbox
+    pub fn open(settings: &OutboxSettings, serial_number: &str) -> Result<Self> {
+        fs::create_dir_all(&settings.dir).map_err(|e| storage::io_error(e, &settings.dir))?;
+
+        let file = settings.file_for(serial_number);
+        let data = Store::open(
+            &settings.storage,
+            &settings.document_for(serial_number),
+            &file,
+            true,
+            || ErrorKind::StorageCorrupt(file.clone()),
+        )?;
+
+        Ok(Outbox {
+            serial_number: serial_number.into(),
+            data: data,
+            limits: settings.limits.clone(),
+        })
+    }
+
+    /// Remove the outbox of a thing, discarding any messages that have not yet been sent
+    pub fn remove(settings: &OutboxSettings, serial_number: &str) -> Result<()> {
+        storage::remove(
+            &settings.storage,
+            &settings.document_for(serial_number),
+            &settings.file_for(serial_number),
+        )
+    }
+
+    /// Queue messages for sending. If the outbox is full, the configured
+    /// `EvictionPolicy` decides which messages are kept
+    pub fn push(&self, msgs: &[PartialThingMessage]) -> Result<()> {
+        let serial_number = &self.serial_number;
+        let limits = &self.limits;
+        let now = now_secs();
+
+        self.data.access_mut(|data| {
+            let expired = data.expire(limits.max_age_secs, now);
+            if expired > 0 {
+                log::warn!("Discarded {} expired messages from outbox", expired);
+            }
+
+            if limits.eviction == EvictionPolicy::Reject &&
+                data.entries.len() + msgs.len() > limits.max_messages
+            {
+                log::warn!(
+                    "Outbox full, {} of {} messages queued",
+                    data.entries.len(),
+                    limits.max_messages
+                );
+                bail!(ErrorKind::OutboxFull(serial_number.clone()));
+            }
+
+            let mut dropped = 0;
+            for msg in msgs {
+                if data.entries.len() >= limits.max_messages {
+                    dropped += 1;
+                    match limits.eviction {
+                        EvictionPolicy::DropNewest => continue,
+                        _ => {
+                            let _ = data.entries.pop_front();
+                        }
+                    }
+                }
+
+                // A zero sized outbox keeps nothing
+                if data.entries.len() < limits.max_messages {
+                    data.entries.push_back(OutboxEntry::new(now, msg));
+                }
+            }
+
+            if dropped > 0 {
+                log::warn!("Outbox full, dropped {} messages", dropped);
+            }
+
+            Ok(())
+        })?
+    }
+
+    /// Hand queued messages to `send` in the order they were queued. Each message is
+    /// removed once it was sent successfully. Sending stops at the first failure,
+    /// leaving that message and all following messages queued for the next attempt
+    pub fn replay<F>(&self, mut send: F) -> Result<()>
+    where
+        F: FnMut(&PartialThingMessage) -> Result<()>,
+    {
+        let max_age = self.limits.max_age_secs;
+        let now = now_secs();
+
+        // Avoid rewriting the outbox file when there is nothing to do
+        let idle = self.data
+            .access(|data| data.entries.is_empty() && !data.has_expired(max_age, now))?;
+        if idle {
+            return Ok(());
+        }
+
+        self.data.access_mut(|data| {
+            let expired = data.expire(max_age, now);
+            if expired > 0 {
+                log::warn!("Discarded {} expired messages from outbox", expired);
+            }
+
+            while let Some(entry) = data.entries.pop_front() {
+                if let Err(e) = send(&entry.message()) {
+                    data.entries.push_front(entry);
+                    return Err(e);
+                }
+            }
+
+            Ok(())
+        })?
+    }
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Serial numbers are chosen by the user, so make sure they are safe to use
+/// as a file name
+fn escape_serial(serial_number: &str) -> String {
+    let mut escaped = String::new();
+    for b in serial_number.bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' => escaped.push(b as char),
+            _ => escaped.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    escaped
+}
+
+/// Determine the outbox directory for a given element file
+pub fn outbox_dir(element_file: &Path) -> PathBuf {
+    element_file
+        .parent()
+        .map(|p| p.join("outbox"))
+        .unwrap_or_else(|| PathBuf::from("outbox"))
+}
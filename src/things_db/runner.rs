@@ -5,83 +5,259 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::path::PathBuf;
-use std::thread;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use log;
 use geeny_api::ThingsApi;
-use mvdb::Mvdb;
-use auth_manager::ServiceCredentials;
+use geeny_api::models::Resource;
+use uuid::Uuid;
+use auth_manager::Credentials;
 
-use std::fs;
-use std::fs::Permissions;
-use std::os::unix::fs::PermissionsExt;
+use errors::*;
+use storage::Store;
 
-use interface;
+use broadcast::Broadcast;
+use secrets::SecretStore;
+use signal::Signal;
+use interface::{self, ExistingThingPolicy, RetryConfig};
+use things_db::{DeliveryOptions, PartialThingMessage, QoS, ThingInfo, ThingMessage};
 use things_db::core::ThingDb;
+use things_db::outbox::{self, OutboxSettings};
 
 pub struct RunnerConfig {
-    pub certificate_storage: PathBuf,
+    pub secrets: Arc<SecretStore>,
     pub mqtt_host: String,
     pub mqtt_port: u16,
+    pub delivery: DeliveryPolicy,
+    pub existing_thing_policy: ExistingThingPolicy,
+    pub retry: RetryConfig,
     pub api: ThingsApi,
+
+    // Messages from the cloud are published here as they arrive, keyed by serial number
+    pub messages: Broadcast<ThingMessage>,
+
+    // Things are published here whenever their state changes or an attempt
+    // fails, keyed by serial number
+    pub states: Broadcast<ThingInfo>,
+}
+
+/// Resolves the MQTT delivery options for subscriptions and outgoing messages.
+/// Options set on a message take precedence over options set for a resource,
+/// which take precedence over the defaults
+pub struct DeliveryPolicy {
+    pub default_qos: QoS,
+    pub default_retain: bool,
+    pub resources: HashMap<String, DeliveryOptions>,
+}
+
+impl DeliveryPolicy {
+    /// QoS level to subscribe to a resource with
+    pub fn subscribe_qos(&self, uri: &str) -> QoS {
+        self.resources
+            .get(uri)
+            .and_then(|opts| opts.qos)
+            .unwrap_or(self.default_qos)
+    }
+
+    /// QoS level and retain flag to publish a message with
+    pub fn publish_options(&self, msg: &PartialThingMessage) -> (QoS, bool) {
+        let resource = self.resources.get(&msg.topic);
+
+        let qos = msg.qos
+            .or_else(|| resource.and_then(|opts| opts.qos))
+            .unwrap_or(self.default_qos);
+        let retain = msg.retain
+            .or_else(|| resource.and_then(|opts| opts.retain))
+            .unwrap_or(self.default_retain);
+
+        (qos, retain)
+    }
 }
 
+// Upper bound for sleeping while no thing needs to be managed
+const MAX_IDLE_SECS: u64 = 60 * 60; // One hour
+
+// Failures to save the things, e.g. because the disk is full, are retried after this delay
+const SAVE_RETRY_SECS: u64 = 5;
+
 pub struct CarePackage<'a> {
     // This changes every time
     pub token_opt: Option<String>,
 
     pub config: &'a RunnerConfig,
+
+    // Resources of each thing type looked up during this step. Things
+    // provisioned together usually share a handful of thing types
+    resources: RefCell<HashMap<Uuid, Vec<Resource>>>,
+}
+
+impl<'a> CarePackage<'a> {
+    /// Resources of a thing type, looked up on the Things API at most once per step
+    pub fn thing_type_resources(&self, token: &str, thing_type: &Uuid) -> Result<Vec<Resource>> {
+        if let Some(resources) = self.resources.borrow().get(thing_type) {
+            return Ok(resources.clone());
+        }
+
+        let resources = self.config
+            .api
+            .get_thing_type_resources(token, thing_type)
+            .map_err(api_error)?;
+
+        self.resources
+            .borrow_mut()
+            .insert(*thing_type, resources.clone());
+        Ok(resources)
+    }
 }
 
 pub struct ThingDbRunner {
-    db: Mvdb<ThingDb>,
+    db: Store<ThingDb>,
     config: RunnerConfig,
-    auth: Mvdb<ServiceCredentials>,
+    auth: Credentials,
+    signal: Signal,
+
+    // Token used in the previous step, to notice logins and refreshes
+    last_token: Option<String>,
+
+    // Whether the previous step failed to save the things. The changes are kept
+    // in memory, and saved by the next step
+    unsaved: bool,
 }
 
 impl ThingDbRunner {
-    pub fn new(config: interface::HubSDKConfig, auth: Mvdb<ServiceCredentials>) -> Self {
+    pub fn new(
+        config: interface::HubSDKConfig,
+        auth: Credentials,
+        secrets: Arc<SecretStore>,
+        signal: Signal,
+    ) -> Result<Self> {
         let run_cfg = RunnerConfig {
-            certificate_storage: config.mqtt_cert_path,
+            secrets: secrets.clone(),
             mqtt_host: config.mqtt_host,
             mqtt_port: config.mqtt_port,
+            delivery: DeliveryPolicy {
+                default_qos: config.mqtt_qos,
+                default_retain: config.mqtt_retain,
+                resources: config.mqtt_resource_options,
+            },
+            existing_thing_policy: config.existing_thing_policy,
+            retry: config.provisioning_retry,
             api: config.api,
+            messages: Broadcast::default(),
+            states: Broadcast::default(),
         };
 
 
         // Create or load DB file, and ensure permissions are set correctly
-        let db_file = Mvdb::from_file_or_default(&config.element_file)
-            .expect("Failed to create element storage!");
-        fs::set_permissions(&config.element_file, Permissions::from_mode(0o600))
-            .expect("Failed to set credentials file permissions");
+        let element_file = config.element_file.clone();
+        let db_file: Store<ThingDb> = Store::open(
+            &config.storage,
+            "elements",
+            &config.element_file,
+            config.reset_corrupt_storage,
+            || ErrorKind::CorruptElementFile(element_file.clone()),
+        )?;
 
-        ThingDbRunner {
+        // Outboxes are stored next to the element file
+        let outbox_settings = OutboxSettings {
+            dir: outbox::outbox_dir(&config.element_file),
+            limits: config.outbox,
+            storage: config.storage.clone(),
+        };
+        db_file.access_mut(move |tdb| {
+            tdb.set_outbox_settings(outbox_settings);
+            tdb.set_secret_store(secrets);
+            tdb.check_certificates();
+        })?;
+
+        Ok(ThingDbRunner {
             db: db_file,
             config: run_cfg,
             auth: auth,
-        }
+            signal: signal,
+            last_token: None,
+            unsaved: false,
+        })
     }
 
     /// Get a thread safe handle to the inner data store
-    pub fn thing_db_handle(&self) -> Mvdb<ThingDb> {
+    pub fn thing_db_handle(&self) -> Store<ThingDb> {
         self.db.clone()
     }
 
-    /// Event loop
+    /// Get a handle used to subscribe to messages from the cloud
+    pub fn message_broadcast(&self) -> Broadcast<ThingMessage> {
+        self.config.messages.clone()
+    }
+
+    /// Get a handle used to follow the state of things
+    pub fn state_broadcast(&self) -> Broadcast<ThingInfo> {
+        self.config.states.clone()
+    }
+
+    /// Event loop, runs until a shutdown is signalled. The loop sleeps until
+    /// a thing needs to be managed, or until it is woken by the `Signal`, e.g.
+    /// because messages were sent or the token changed
     pub fn run(&mut self) {
-        // TODO, init steps? Load from a file?
         loop {
-            self.step();
-            #[allow(deprecated)] thread::sleep_ms(250); // TODO IR - Use the scheduler thing
+            let idle = Duration::from_secs(MAX_IDLE_SECS);
+            let timeout = match self.step() {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if deadline > now {
+                        (deadline - now).min(idle)
+                    } else {
+                        Duration::from_secs(0)
+                    }
+                }
+                None => idle,
+            };
+
+            if self.signal.wait(timeout) {
+                break;
+            }
         }
-        // TODO, closeout steps?
+
+        // Close all connections, and stop delivering messages
+        if let Err(e) = self.db.access_mut(|tdb| tdb.disconnect_all()) {
+            log::error!("Failed to save things: {}", e);
+        }
+        self.config.messages.close();
+        self.config.states.close();
     }
 
-    /// Single step of the event loop
-    fn step(&mut self) {
-        let token_opt = self.auth
-            .access(|auth| auth.token.clone())
-            .expect("Unable to access Auth!");
+    /// Single step of the event loop. Returns the time at which the next step
+    /// is needed, if any
+    fn step(&mut self) -> Option<Instant> {
+        // Changes of the previous step are saved before new changes are made, e.g.
+        // before a thing replaced by a certificate rotation is deleted
+        if self.unsaved {
+            if let Err(e) = self.db.access_mut(|_| ()) {
+                return self.save_failed(&e);
+            }
+            self.unsaved = false;
+        }
+
+        let token_opt = self.auth.token();
+        let token_changed = token_opt != self.last_token;
+        self.last_token = token_opt.clone();
+
+        // Avoid locking and writing the ThingDb while there is nothing to do
+        let next = match self.db.access(|tdb| tdb.next_deadline()) {
+            Ok(next) => next,
+            Err(e) => {
+                log::error!("Failed to read things: {}", e);
+                return Some(Instant::now() + Duration::from_secs(SAVE_RETRY_SECS));
+            }
+        };
+        let due = next.map(|at| at <= Instant::now()).unwrap_or(false);
+
+        if !due && !token_changed {
+            return next;
+        }
 
         let x = CarePackage {
             // This changes every time
@@ -89,10 +265,29 @@ impl ThingDbRunner {
 
             // This is constant across lifetime of self
             config: &self.config,
+
+            resources: RefCell::new(HashMap::new()),
         };
 
-        self.db
-            .access_mut(move |tdb| { tdb.manage(x); })
-            .expect("Failed to access ThingDb!");
+        let managed = self.db.access_mut(move |tdb| {
+            // Things waiting for a token can continue now
+            if token_changed {
+                tdb.wake_all();
+            }
+
+            tdb.manage(x)
+        });
+
+        match managed {
+            Ok(next) => next,
+            Err(e) => self.save_failed(&e),
+        }
+    }
+
+    /// Keep the changes in memory, and try to save them again after a delay
+    fn save_failed(&mut self, error: &Error) -> Option<Instant> {
+        log::error!("Failed to save things, retrying in {}s: {}", SAVE_RETRY_SECS, error);
+        self.unsaved = true;
+        Some(Instant::now() + Duration::from_secs(SAVE_RETRY_SECS))
     }
 }
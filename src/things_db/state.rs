@@ -6,20 +6,28 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::fmt;
-use std::fs::{self, File, Permissions};
-use std::io::prelude::*;
-use std::os::unix::fs::PermissionsExt;
 use std::path::PathBuf;
-use std::sync::mpsc::{Sender, TryIter};
-use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use log;
 use rumqtt::{self, MqttClient};
+use uuid::Uuid;
 
+use broadcast::Broadcast;
 use errors::*;
+use interface::ExistingThingPolicy;
 use geeny_api::ThingsApi;
 use geeny_api::models::{Resource, ResourceMethod, Thing, ThingRequest};
-use things_db::PartialThingMessage;
+use secrets::{self, SecretStore};
+use things_db::{PartialThingMessage, ThingInfo, ThingMessage, ThingState};
+use things_db::certificate;
+use things_db::inbox::{self, Inbox};
+use things_db::outbox::{self, Outbox};
+use things_db::runner::{CarePackage, DeliveryPolicy};
+
+// Warn about certificates expiring within this time, once a day
+const EXPIRY_WARNING_SECS: u64 = 30 * 24 * 60 * 60; // 30 days
+const EXPIRY_WARNING_INTERVAL_SECS: u64 = 24 * 60 * 60; // One day
 
 /// `ThingSyncState` is a three part state machine. The three states are:
 ///   * `Created`: We have received a local request to create a Geeny
@@ -31,14 +39,24 @@ use things_db::PartialThingMessage;
 ///   * `Active`: All necessary information has been gathered. The Thing will attempt
 ///       to establish and maintain a data stream connection
 ///
-/// State transitions from one `ThingSyncState` to another occur by returning an
-///   Option<Self>, where Some(Self) notes that the transition was successful,
+/// Additionally, an `Active` Thing enters `RotatingCertificates` when new certificates
+///   are requested. It is replaced by a new Thing on the Geeny Cloud, and continues
+///   with `GatheringMetadata`. The replaced Thing is deleted once the new Thing has
+///   been saved, see `HubThing::replaced`
+///
+/// A Thing which the Geeny Cloud keeps rejecting enters `Failed`. It is no longer
+///   managed, until it is provisioned again
+///
+/// State transitions from one `ThingSyncState` to another occur by returning a
+///   Result<Self>, where Ok(Self) notes that the transition was successful,
 ///   and the current state should be replaced with a new one
 #[derive(Serialize, Deserialize)]
 pub enum ThingSyncState {
     Created(ThingRequest),
     GatheringMetadata(Thing),
     Active(MetaThing),
+    RotatingCertificates(Thing),
+    Failed(ThingRequest),
 }
 
 impl fmt::Display for ThingSyncState {
@@ -55,6 +73,10 @@ impl fmt::Display for ThingSyncState {
                 t.thing.serial_number,
                 t.thing.id
             ),
+            RotatingCertificates(ref t) => {
+                write!(f, "Rotating: s/n: {}, gtid: {}", t.serial_number, t.id)
+            }
+            Failed(ref t) => write!(f, "Failed: s/n: {}", t.serial_number),
         }
     }
 }
@@ -66,97 +88,342 @@ impl ThingSyncState {
         api: &ThingsApi,
         token: &str,
         thing_request: &ThingRequest,
-    ) -> Option<Self> {
+        policy: ExistingThingPolicy,
+        secrets: &SecretStore,
+    ) -> Result<Self> {
         // First check if there is an existing device matching this serial number
-        let existing = api.get_thing_by_serial(token, &thing_request.serial_number);
+        let existing = api.get_thing_by_serial(token, &thing_request.serial_number)
+            .map_err(api_error)?;
 
-        match existing {
-            Ok(Some(_new_thing)) => {
-                // TODO: restore after we can retrieve certs for existing devices
-                // https://jira.geeny.io/browse/DI-211
-                log::error!(
+        match (existing, policy) {
+            (Some(existing_thing), ExistingThingPolicy::Adopt) => {
+                return Self::adopt_thing(api, token, thing_request, existing_thing, secrets);
+            }
+            (Some(existing_thing), ExistingThingPolicy::Replace) => {
+                return Self::replace_thing(api, token, thing_request, existing_thing);
+            }
+            (Some(existing_thing), ExistingThingPolicy::Fail) => {
+                bail!(ErrorKind::ThingExists(
+                    existing_thing.serial_number,
+                    existing_thing.id
+                ));
+            }
+            (Some(_), ExistingThingPolicy::CreateDuplicate) => {
+                log::warn!(
                     "Found existing thing with SN:{}, creating new device anyway",
                     thing_request.serial_number
                 );
-                // return Some(ThingInstance::from_thing(new_thing))
-            }
-            Ok(None) => {}
-            Err(e) => {
-                log::error!("Failed to query existing things: {}", e);
-                return None;
             }
+            (None, _) => {}
         };
 
         // Doesn't exist, make a new one
-        match api.create_thing(token, thing_request) {
-            Ok(new_thing) => {
-                // Transition from Created to GatheringMetadata
-                Some(ThingSyncState::GatheringMetadata(new_thing))
-            }
-            Err(e) => {
-                log::error!("Failed to create thing: {:?}", e);
-                // No transition on failure
-                None
-            }
+        Self::register_thing(api, token, thing_request)
+    }
+
+    /// Reuse a thing that already exists on the Geeny cloud. The Things API does
+    /// not hand out certificates of existing things (https://jira.geeny.io/browse/DI-211),
+    /// so certificates kept from an earlier pairing are used. Without them, the
+    /// thing is replaced to obtain new certificates
+    fn adopt_thing(
+        api: &ThingsApi,
+        token: &str,
+        thing_request: &ThingRequest,
+        existing_thing: Thing,
+        secrets: &SecretStore,
+    ) -> Result<Self> {
+        if existing_thing.certs.is_none() && !has_certificates(secrets, &existing_thing.id)? {
+            log::warn!(
+                "No certificates for existing thing with SN:{}, gtid: {}",
+                existing_thing.serial_number,
+                existing_thing.id
+            );
+            return Self::replace_thing(api, token, thing_request, existing_thing);
         }
+
+        log::info!(
+            "Adopting existing thing with SN:{}, gtid: {}",
+            existing_thing.serial_number,
+            existing_thing.id
+        );
+
+        // Transition from Created to GatheringMetadata
+        Ok(ThingSyncState::GatheringMetadata(existing_thing))
+    }
+
+    /// Replace a thing that already exists on the Geeny cloud by a new thing, which
+    /// comes with fresh certificates. No duplicate remains
+    fn replace_thing(
+        api: &ThingsApi,
+        token: &str,
+        thing_request: &ThingRequest,
+        existing_thing: Thing,
+    ) -> Result<Self> {
+        log::warn!(
+            "Replacing existing thing with SN:{}, gtid: {}",
+            existing_thing.serial_number,
+            existing_thing.id
+        );
+
+        api.delete_thing(token, &existing_thing.id).map_err(api_error)?;
+
+        Self::register_thing(api, token, thing_request)
+    }
+
+    /// Create a new thing on the Geeny cloud
+    fn register_thing(api: &ThingsApi, token: &str, thing_request: &ThingRequest) -> Result<Self> {
+        let new_thing = api.create_thing(token, thing_request).map_err(api_error)?;
+
+        // Transition from Created to GatheringMetadata
+        Ok(ThingSyncState::GatheringMetadata(new_thing))
     }
 
     /// We have created the device in the Geeny cloud, we now need to get some
     /// associated metadata before we can establish an MQTT connection
     pub fn gather_thing_metadata(
-        api: &ThingsApi,
+        package: &CarePackage,
         token: &str,
         geeny_thing: &Thing,
-    ) -> Option<Self> {
-        // get all resources for this thing type
-        let meta_req = api.get_thing_type_resources(token, &geeny_thing.thing_type);
-
-        // if we got all the data we needed, we can transition state
-        if let Ok(meta) = meta_req {
-            Some(ThingSyncState::Active(MetaThing {
-                thing: geeny_thing.clone(),
-                resources: meta,
-                mqtt_handle: None,
-                ca_file_name: None,
-                cert_file_name: None,
-                key_file_name: None,
-            }))
-        } else {
-            // No transition on failure
-            None
+    ) -> Result<Self> {
+        // get all resources for this thing type, shared with other things of the same type
+        let meta = package.thing_type_resources(token, &geeny_thing.thing_type)?;
+
+        // we got all the data we needed, we can transition state
+        Ok(ThingSyncState::Active(MetaThing {
+            thing: geeny_thing.clone(),
+            resources: meta,
+            mqtt_handle: None,
+            ca_file_name: None,
+            cert_file_name: None,
+            key_file_name: None,
+            cert_expiry: None,
+            expiry_warned: None,
+        }))
+    }
+
+    /// Whether the Geeny cloud rejected the thing too often
+    pub fn is_failed(&self) -> bool {
+        match *self {
+            ThingSyncState::Failed(_) => true,
+            _ => false,
         }
     }
 
-    pub fn consume(self) {
+    /// The Geeny Thing ID, once the thing has been created on the Geeny cloud
+    pub fn thing_id(&self) -> Option<Uuid> {
+        use self::ThingSyncState::*;
+
+        match *self {
+            Created(_) | Failed(_) => None,
+            GatheringMetadata(ref t) => Some(t.id),
+            Active(ref t) => Some(t.thing.id),
+            RotatingCertificates(ref t) => Some(t.id),
+        }
+    }
+
+    /// Summarize the current state of the thing
+    pub fn info(&self, serial_number: &str) -> ThingInfo {
+        use self::ThingSyncState::*;
+
+        let (state, mqtt_client_started, resources, cert_expires_at) = match *self {
+            Created(_) => (ThingState::Created, false, vec![], None),
+            GatheringMetadata(_) => (ThingState::GatheringMetadata, false, vec![], None),
+            Active(ref t) => (
+                ThingState::Active,
+                t.mqtt_handle.is_some(),
+                t.resources.clone(),
+                t.cert_expiry,
+            ),
+            RotatingCertificates(_) => (ThingState::RotatingCertificates, false, vec![], None),
+            Failed(_) => (ThingState::Failed, false, vec![], None),
+        };
+
+        ThingInfo {
+            serial_number: serial_number.into(),
+            thing_id: self.thing_id(),
+            state: state,
+            mqtt_client_started: mqtt_client_started,
+            resources: resources,
+            cert_expires_at: cert_expires_at,
+            previous_thing_id: None,
+            last_failure: None,
+        }
+    }
+
+    /// Disconnect and shutdown MQTT, if connected
+    pub fn disconnect(&mut self) {
+        if let ThingSyncState::Active(ref mut meta) = *self {
+            meta.disconnect();
+        }
+    }
+
+    /// Make sure the certificates of an active thing are in the secret store, and
+    /// can be parsed. Returns what is wrong with them if they are missing or damaged.
+    /// Failures to read the secret store, e.g. to decrypt a secret, are errors
+    pub fn verify_certificates(&self, secrets: &SecretStore) -> Result<Option<String>> {
+        let meta = match *self {
+            ThingSyncState::Active(ref meta) => meta,
+            _ => return Ok(None),
+        };
+
+        // Certificates are moved to the secret store once connected
+        if meta.thing.certs.is_some() {
+            return Ok(None);
+        }
+
+        for key in &secrets::certificate_keys(&meta.thing.id) {
+            let pem = match secrets.get(key)? {
+                Some(pem) => pem,
+                None => return Ok(Some(format!("Missing certificate {}", key))),
+            };
+
+            if let Err(e) = certificate::validate(key, &pem) {
+                return Ok(Some(format!("Invalid certificate {}: {}", key, e)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Start over, creating the thing on the Geeny cloud again. This obtains
+    /// new certificates
+    pub fn reprovision(&mut self) {
+        use self::ThingSyncState::*;
+
+        let request = match *self {
+            Created(_) => return,
+            Failed(ref t) => copy_request(t),
+            GatheringMetadata(ref t) | RotatingCertificates(ref t) => thing_request(t),
+            Active(ref mut t) => {
+                t.disconnect();
+                thing_request(&t.thing)
+            }
+        };
+
+        *self = Created(request);
+    }
+
+    /// Give up on a thing the Geeny cloud keeps rejecting
+    pub fn fail(&mut self) {
+        use self::ThingSyncState::*;
+
+        let request = match *self {
+            Created(ref t) | Failed(ref t) => copy_request(t),
+            GatheringMetadata(ref t) | RotatingCertificates(ref t) => thing_request(t),
+            Active(ref mut t) => {
+                t.disconnect();
+                thing_request(&t.thing)
+            }
+        };
+
+        *self = Failed(request);
+    }
+
+    /// Disconnect the thing, and remove its certificates from the secret store
+    pub fn consume(self, secrets: Option<&SecretStore>) {
         // Disconnect and shutdown MQTT
         // Extraction discards the IO channels
-        if let ThingSyncState::Active(mut meta) = self {
-            if let Some(hdlr) = meta.mqtt_handle {
-                // Disconnect and Shutdown, generally disregarding
-                // any errors in the closing process
-                if let Err(e) = hdlr.disconnect() {
-                    log::error!("Failed to disconnect handler: {}", e);
-                }
-                if let Err(e) = hdlr.shutdown() {
-                    log::error!("Failed to shutdown MQTT: {}", e);
-                }
+        let thing_id = match self {
+            ThingSyncState::Active(mut meta) => {
+                meta.disconnect();
+                meta.thing.id
+            }
+            ThingSyncState::RotatingCertificates(thing) => thing.id,
+            _ => return,
+        };
+
+        let secrets = match secrets {
+            Some(secrets) => secrets,
+            None => {
+                log::error!("No secret store, keeping certificates of {}", thing_id);
+                return;
             }
+        };
 
-            // Explicitly discard the MQTT handle
-            meta.mqtt_handle = None;
+        delete_certificates(secrets, &thing_id);
+    }
 
-            for f in &[meta.ca_file_name, meta.cert_file_name, meta.key_file_name] {
-                if let Some(ref file) = *f {
-                    match fs::remove_file(file) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            log::error!("failed to delete file \"{:?}\", error: {}", file, e);
-                        }
-                    }
-                }
+    /// Request new certificates for an active thing. The MQTT connection is
+    /// closed until the new certificates have been obtained
+    pub fn start_rotation(&mut self, serial_number: &str) -> Result<()> {
+        let thing = match *self {
+            ThingSyncState::Active(ref mut meta) => {
+                meta.disconnect();
+                meta.thing.clone()
             }
+            _ => bail!(ErrorKind::ThingNotActive(serial_number.into())),
+        };
+
+        *self = ThingSyncState::RotatingCertificates(thing);
+        Ok(())
+    }
+
+    /// Obtain new certificates for a thing. The Things API only hands out
+    /// certificates when a thing is created, so the thing is replaced by a new
+    /// thing with the same serial number. The old thing is kept until the new
+    /// thing has been saved, see `delete_replaced_thing`
+    pub fn rotate_certificates(api: &ThingsApi, token: &str, old_thing: &Thing) -> Result<Self> {
+        let new_state = Self::register_thing(api, token, &thing_request(old_thing))?;
+
+        log::info!(
+            "Obtained new certificates for SN:{}, replacing gtid: {}",
+            old_thing.serial_number,
+            old_thing.id
+        );
+
+        // Transition from RotatingCertificates to GatheringMetadata
+        Ok(new_state)
+    }
+}
+
+/// Delete a thing replaced by a certificate rotation from the Geeny cloud, along
+/// with its certificates
+pub fn delete_replaced_thing(
+    api: &ThingsApi,
+    token: &str,
+    thing_id: &Uuid,
+    secrets: &SecretStore,
+) -> Result<()> {
+    api.delete_thing(token, thing_id).map_err(api_error)?;
+
+    log::info!("Deleted replaced thing {}", thing_id);
+    delete_certificates(secrets, thing_id);
+    Ok(())
+}
+
+/// Whether all certificates of a thing are in the secret store
+fn has_certificates(secrets: &SecretStore, thing_id: &Uuid) -> Result<bool> {
+    for key in &secrets::certificate_keys(thing_id) {
+        if secrets.get(key)?.is_none() {
+            return Ok(false);
         }
     }
+
+    Ok(true)
+}
+
+fn delete_certificates(secrets: &SecretStore, thing_id: &Uuid) {
+    for key in &secrets::certificate_keys(thing_id) {
+        if let Err(e) = secrets.delete(key) {
+            log::error!("failed to delete secret \"{}\", error: {}", key, e);
+        }
+    }
+}
+
+fn thing_request(thing: &Thing) -> ThingRequest {
+    ThingRequest {
+        name: thing.name.clone(),
+        serial_number: thing.serial_number.clone(),
+        thing_type: thing.thing_type,
+    }
+}
+
+fn copy_request(request: &ThingRequest) -> ThingRequest {
+    ThingRequest {
+        name: request.name.clone(),
+        serial_number: request.serial_number.clone(),
+        thing_type: request.thing_type,
+    }
 }
 
 /// `MetaThing` is the final state of the `ThingSyncState` state machine.
@@ -171,45 +438,80 @@ pub struct MetaThing {
     pub cert_file_name: Option<PathBuf>,
     pub key_file_name: Option<PathBuf>,
 
+    // Seconds since the unix epoch
+    #[serde(default)]
+    pub cert_expiry: Option<u64>,
+
     #[serde(skip)] pub mqtt_handle: Option<MqttClient>,
+    #[serde(skip)] pub expiry_warned: Option<Instant>,
 }
 
 impl MetaThing {
+    /// Disconnect and shutdown the MQTT connection, if any
+    pub fn disconnect(&mut self) {
+        // Explicitly discard the MQTT handle
+        if let Some(hdlr) = self.mqtt_handle.take() {
+            // Disconnect and Shutdown, generally disregarding
+            // any errors in the closing process
+            if let Err(e) = hdlr.disconnect() {
+                log::error!("Failed to disconnect handler: {}", e);
+            }
+            if let Err(e) = hdlr.shutdown() {
+                log::error!("Failed to shutdown MQTT: {}", e);
+            }
+        }
+    }
+
     /// Attempt to establish an MQTT connection for a device
     pub fn connect_mqtt(
         &mut self,
-        mailbox: Sender<PartialThingMessage>,
-        cert_storage: &PathBuf,
+        inbox: Inbox,
+        secrets: &SecretStore,
         mqtt_host: &str,
         mqtt_port: u16,
+        delivery: &DeliveryPolicy,
+        subscribers: Broadcast<ThingMessage>,
     ) -> Result<()> {
-        let certs = self.thing
-            .certs
-            .as_ref()
-            .ok_or_else(|| Error::from("Missing certificates!"))?;
+        let keys = secrets::certificate_keys(&self.thing.id);
+
+        // Certificates received from the Geeny cloud are moved to the secret store,
+        // so they are no longer kept in the element file
+        let moved = match self.thing.certs {
+            Some(ref certs) => {
+                for (key, body) in keys.iter().zip(&[&certs.ca, &certs.cert, &certs.key]) {
+                    secrets.put(key, body.as_bytes())?;
+                }
+                true
+            }
+            None => false,
+        };
+
+        if moved {
+            self.thing.certs = None;
+        }
 
         // AJM - This is not the best idea to write the certificates to a file.
         // Doing it for now, because the library (OpenSSL, depended on by
         // Rumqtt) only supports certificates from files, not strings.
-        // TODO 2  - Also not necessary to write to file if it already exists
-        let ca_file_name = cert_storage.join(&format!("{}.ca.crt", self.thing.id));
-        let cert_file_name = cert_storage.join(&format!("{}.crt", self.thing.id));
-        let key_file_name = cert_storage.join(&format!("{}.key", self.thing.id));
-
-        for &(fname, body) in &[
-            (&ca_file_name, &certs.ca),
-            (&cert_file_name, &certs.cert),
-            (&key_file_name, &certs.key),
-        ] {
-            // Scope to ensure file closed and written
-            {
-                let mut file = File::create(fname).chain_err(|| "Failed to create file!")?;
-                file.write_all(body.as_bytes())
-                    .chain_err(|| "Failed to write to file")?;
-                file.sync_all().chain_err(|| "Failed to sync file")?;
-            }
-            fs::set_permissions(&fname, Permissions::from_mode(0o600))
-                .expect("Failed to set certificate file permissions");
+        // The secret store only writes files whose contents changed
+        let ca_file_name = secrets
+            .file_path(&keys[0])
+            .chain_err(|| "Missing certificates!")?;
+        let cert_file_name = secrets
+            .file_path(&keys[1])
+            .chain_err(|| "Missing certificates!")?;
+        let key_file_name = secrets
+            .file_path(&keys[2])
+            .chain_err(|| "Missing certificates!")?;
+
+        // Keep track of the validity of the client certificate
+        match secrets.get(&keys[1]) {
+            Ok(Some(pem)) => match certificate::expiry(&pem) {
+                Ok(expiry) => self.cert_expiry = Some(expiry),
+                Err(e) => log::error!("Failed to read certificate expiry: {}", e),
+            },
+            Ok(None) => {}
+            Err(e) => log::error!("Failed to read certificate: {}", e),
         }
 
         // Save file names
@@ -228,33 +530,24 @@ impl MetaThing {
             .set_keep_alive(5)
             .set_reconnect(10);
 
-        // MQTT output channel must be ARC, in case multiple messages arrive at once
-        let mqtt_channel = Arc::new(Mutex::new(mailbox));
+        let serial_number = self.thing.serial_number.clone();
 
         let msg_handler = rumqtt::MqttCallback::new().on_message(move |message| {
-            let payload = String::from_utf8_lossy(message.payload.as_ref()).into_owned();
+            let payload = message.payload.as_ref().clone();
             log::info!(
-                "Incoming MQTT message: {:?} payload: >>{}<<",
+                "Incoming MQTT message: {:?} payload: {} bytes",
                 message,
-                payload
+                payload.len()
             );
 
-            let sender = match mqtt_channel.lock() {
-                Ok(tx) => tx,
-                Err(e) => {
-                    log::error!("Failed to lock mqtt sender: {}", e);
-                    return;
-                }
-            };
-
-            let rslt = sender.send(PartialThingMessage {
+            let msg = PartialThingMessage {
                 topic: message.topic.to_string(),
                 msg: payload,
-            });
+                qos: None,
+                retain: None,
+            };
 
-            if let Err(e) = rslt {
-                log::error!("Failed to send MQTT message via channel: {}", e);
-            }
+            inbox::deliver(&subscribers, &inbox, &serial_number, msg);
         });
 
         let mut client =
@@ -263,7 +556,9 @@ impl MetaThing {
         let subscribes: Vec<(&str, rumqtt::QoS)> = self.resources
                     .iter()
                     .filter_map(|r| match r.method {
-                        ResourceMethod::Sub => Some((r.uri.as_str(), rumqtt::QoS::Level0)), // TODO variable QoS?
+                        ResourceMethod::Sub => {
+                            Some((r.uri.as_str(), delivery.subscribe_qos(&r.uri).into()))
+                        }
                         ResourceMethod::Pub => None,
                     })
                     .collect();
@@ -279,27 +574,61 @@ impl MetaThing {
         Ok(())
     }
 
-    pub fn process_messages(&mut self, msgs_from_hub: TryIter<PartialThingMessage>) -> Result<()> {
+    /// Warn once a day while the client certificate is about to expire
+    pub fn warn_on_expiry(&mut self) {
+        let expiry = match self.cert_expiry {
+            Some(expiry) => expiry,
+            None => return,
+        };
+
+        let now = outbox::now_secs();
+        if expiry > now + EXPIRY_WARNING_SECS {
+            return;
+        }
+
+        let interval = Duration::from_secs(EXPIRY_WARNING_INTERVAL_SECS);
+        if self.expiry_warned.map(|t| t.elapsed() < interval).unwrap_or(false) {
+            return;
+        }
+        self.expiry_warned = Some(Instant::now());
+
+        if expiry <= now {
+            log::error!(
+                "Certificate of SN:{} has expired, please rotate it",
+                self.thing.serial_number
+            );
+        } else {
+            log::warn!(
+                "Certificate of SN:{} expires in {} days, please rotate it",
+                self.thing.serial_number,
+                (expiry - now) / (24 * 60 * 60)
+            );
+        }
+    }
+
+    pub fn process_messages(&mut self, outbox: &Outbox, delivery: &DeliveryPolicy) -> Result<()> {
         // Messages from the cloud already are "pushed" to the final queue.
-        // Messages from the hub need to be "pushed" to the cloud.
+        // Messages from the hub need to be "pushed" to the cloud. They stay
+        // in the outbox until they have been published
         if let Some(ref mut m_handle) = self.mqtt_handle {
-            for msg in msgs_from_hub {
-                log::info!("Sending: {:?}", msg);
-
-                // TODO: https://jira.geeny.io/browse/DI-194
-                use std::ascii::AsciiExt;
-                let ascii_msg = msg.msg.chars().filter(|c| c.is_ascii()).collect::<String>();
-
-                let tx_bytes = ascii_msg.into_bytes();
-
-                m_handle
-                    .publish(
-                        &msg.topic,
-                        rumqtt::QoS::Level0, // TODO configurable?
-                        tx_bytes,
-                    )
-                    .chain_err(|| "Failed to publish")?;
-            }
+            outbox.replay(|msg| {
+                let (qos, retain) = delivery.publish_options(msg);
+                log::info!(
+                    "Sending: {} bytes to {}, qos: {:?}, retain: {}",
+                    msg.msg.len(),
+                    msg.topic,
+                    qos,
+                    retain
+                );
+
+                let rslt = if retain {
+                    m_handle.retained_publish(&msg.topic, qos.into(), msg.msg.clone())
+                } else {
+                    m_handle.publish(&msg.topic, qos.into(), msg.msg.clone())
+                };
+
+                rslt.chain_err(|| "Failed to publish")
+            })?;
         }
 
         Ok(())